@@ -0,0 +1,907 @@
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, NaiveDateTime, Utc};
+use csv::{ReaderBuilder, WriterBuilder};
+use rust_bert::pipelines::sentiment::Sentiment;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::config::AppConfig;
+use crate::error::{LambdaError, StorageErrorKind};
+use crate::storage::{Storage, WriteCondition};
+use crate::Polarity;
+
+// 全部时间的总计
+pub const ALL_TIME_BUCKET: &str = "all";
+const DAY_FORMAT: &str = "%Y-%m-%d";
+const HOUR_FORMAT: &str = "%Y-%m-%dT%H";
+
+// 时间桶的粒度：按天 "2024-04-01"，按小时 "2024-04-01T13" (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granularity {
+    Day,
+    Hour,
+}
+
+impl Granularity {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "day" => Some(Granularity::Day),
+            "hour" => Some(Granularity::Hour),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Granularity::Day => "day",
+            Granularity::Hour => "hour",
+        }
+    }
+
+    pub fn bucket(&self, at: DateTime<Utc>) -> String {
+        match self {
+            Granularity::Day => at.format(DAY_FORMAT).to_string(),
+            Granularity::Hour => at.format(HOUR_FORMAT).to_string(),
+        }
+    }
+
+    // 根据 bucket 字符串判断粒度，"all" 返回 None
+    pub fn of_bucket(bucket: &str) -> Option<Self> {
+        match bucket.len() {
+            10 => Some(Granularity::Day),
+            13 => Some(Granularity::Hour),
+            _ => None,
+        }
+    }
+
+    // 把 "2024-04-01" 或 "2024-04-01T13" 规范成该粒度下的 bucket；
+    // end = true 时日期会扩展到当天最后一个小时，便于作为闭区间的终点
+    pub fn normalize(&self, value: &str, end: bool) -> Option<String> {
+        let at = if let Ok(date) = NaiveDate::parse_from_str(value, DAY_FORMAT) {
+            let hour = if end { 23 } else { 0 };
+            date.and_hms_opt(hour, 0, 0)?
+        } else {
+            NaiveDateTime::parse_from_str(&format!("{}:00:00", value), "%Y-%m-%dT%H:%M:%S").ok()?
+        };
+        Some(self.bucket(at.and_utc()))
+    }
+}
+
+fn all_time_bucket() -> String {
+    ALL_TIME_BUCKET.to_string()
+}
+
+// (时间桶, 情感) -> 计数
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CountKey {
+    pub bucket: String,
+    pub sentiment: String,
+}
+
+// 置信度直方图的分箱数：[0, 0.1), [0.1, 0.2), ..., [0.9, 1.0]
+pub const HISTOGRAM_BINS: usize = 10;
+
+// 每个 (时间桶, 情感) 的计数和分数统计
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Aggregate {
+    pub count: i32,
+    // 带分数的条数；旧格式的文件只有计数，没有分数
+    pub scored: i32,
+    pub score_sum: f64,
+    pub score_sq_sum: f64,
+    pub score_min: Option<f64>,
+    pub score_max: Option<f64>,
+    pub histogram: [i32; HISTOGRAM_BINS],
+}
+
+impl Aggregate {
+    pub fn observe(&mut self, score: Option<f64>) {
+        self.count += 1;
+        if let Some(score) = score {
+            self.scored += 1;
+            self.score_sum += score;
+            self.score_sq_sum += score * score;
+            self.score_min = Some(self.score_min.map_or(score, |min| min.min(score)));
+            self.score_max = Some(self.score_max.map_or(score, |max| max.max(score)));
+            let bin = ((score.clamp(0.0, 1.0) * HISTOGRAM_BINS as f64) as usize).min(HISTOGRAM_BINS - 1);
+            self.histogram[bin] += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Aggregate) {
+        self.count += other.count;
+        self.scored += other.scored;
+        self.score_sum += other.score_sum;
+        self.score_sq_sum += other.score_sq_sum;
+        self.score_min = merge_option(self.score_min, other.score_min, f64::min);
+        self.score_max = merge_option(self.score_max, other.score_max, f64::max);
+        for (bin, count) in self.histogram.iter_mut().zip(other.histogram) {
+            *bin += count;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0 && self.scored == 0
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.scored > 0).then(|| self.score_sum / f64::from(self.scored))
+    }
+
+    // 总体标准差
+    pub fn std_dev(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        let variance = self.score_sq_sum / f64::from(self.scored) - mean * mean;
+        Some(variance.max(0.0).sqrt())
+    }
+}
+
+fn merge_option(a: Option<f64>, b: Option<f64>, pick: fn(f64, f64) -> f64) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(pick(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+pub type Counts = HashMap<CountKey, Aggregate>;
+
+// CSV 的表头版本：
+//   v1: Sentiment,Count
+//   v2: Bucket,Sentiment,Count
+//   v3: Bucket,Sentiment,Count,Scored,ScoreSum,ScoreSqSum,ScoreMin,ScoreMax,Histogram
+// 写入时第一行为 "# sentiment-aggregate schema=3"；没有这一行的旧文件按表头识别版本，
+// 缺少的列使用默认值，下次写入时自动升级为当前版本
+pub const SCHEMA_VERSION: u32 = 3;
+const SCHEMA_MARKER: &str = "# sentiment-aggregate schema=";
+const HEADERS: [&[&str]; 3] = [
+    &["Sentiment", "Count"],
+    &["Bucket", "Sentiment", "Count"],
+    &["Bucket", "Sentiment", "Count", "Scored", "ScoreSum", "ScoreSqSum", "ScoreMin", "ScoreMax", "Histogram"],
+];
+
+fn header_for(version: u32) -> &'static [&'static str] {
+    HEADERS[version as usize - 1]
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SentimentRecord {
+    // 旧格式没有 Bucket 列，全部视为总计
+    #[serde(rename = "Bucket", default = "all_time_bucket")]
+    pub bucket: String,
+    #[serde(rename = "Sentiment")]
+    pub sentiment: String,
+    #[serde(rename = "Count")]
+    pub count: i32,
+    #[serde(rename = "Scored", default)]
+    pub scored: i32,
+    #[serde(rename = "ScoreSum", default)]
+    pub score_sum: f64,
+    #[serde(rename = "ScoreSqSum", default)]
+    pub score_sq_sum: f64,
+    #[serde(rename = "ScoreMin", default)]
+    pub score_min: Option<f64>,
+    #[serde(rename = "ScoreMax", default)]
+    pub score_max: Option<f64>,
+    // 以空格分隔的各分箱计数
+    #[serde(rename = "Histogram", default, with = "histogram_column")]
+    pub histogram: [i32; HISTOGRAM_BINS],
+}
+
+impl SentimentRecord {
+    fn new(key: &CountKey, aggregate: &Aggregate) -> Self {
+        SentimentRecord {
+            bucket: key.bucket.clone(),
+            sentiment: key.sentiment.clone(),
+            count: aggregate.count,
+            scored: aggregate.scored,
+            score_sum: aggregate.score_sum,
+            score_sq_sum: aggregate.score_sq_sum,
+            score_min: aggregate.score_min,
+            score_max: aggregate.score_max,
+            histogram: aggregate.histogram,
+        }
+    }
+
+    fn into_entry(self) -> (CountKey, Aggregate) {
+        let key = CountKey { bucket: self.bucket, sentiment: self.sentiment };
+        let aggregate = Aggregate {
+            count: self.count,
+            scored: self.scored,
+            score_sum: self.score_sum,
+            score_sq_sum: self.score_sq_sum,
+            score_min: self.score_min,
+            score_max: self.score_max,
+            histogram: self.histogram,
+        };
+        (key, aggregate)
+    }
+}
+
+mod histogram_column {
+    use super::HISTOGRAM_BINS;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(histogram: &[i32; HISTOGRAM_BINS], serializer: S) -> Result<S::Ok, S::Error> {
+        let value: Vec<String> = histogram.iter().map(|count| count.to_string()).collect();
+        serializer.serialize_str(&value.join(" "))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[i32; HISTOGRAM_BINS], D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let mut histogram = [0; HISTOGRAM_BINS];
+        if value.trim().is_empty() {
+            return Ok(histogram);
+        }
+        let bins: Vec<&str> = value.split_whitespace().collect();
+        if bins.len() != HISTOGRAM_BINS {
+            return Err(D::Error::custom(format!("expected {} histogram bins, found {}", HISTOGRAM_BINS, bins.len())));
+        }
+        for (slot, bin) in histogram.iter_mut().zip(bins) {
+            *slot = bin.parse().map_err(D::Error::custom)?;
+        }
+        Ok(histogram)
+    }
+}
+
+// 读取第一行的版本标记，没有标记时返回 None
+fn schema_marker(key: &str, content: &str) -> Result<Option<u32>, LambdaError> {
+    let first_line = content.lines().next().unwrap_or("").trim();
+    let Some(version) = first_line.strip_prefix(SCHEMA_MARKER) else {
+        if first_line.starts_with('#') {
+            return Err(LambdaError::unsupported_schema(key, format!("unrecognized marker {:?}", first_line)));
+        }
+        return Ok(None);
+    };
+    match version.parse::<u32>() {
+        Ok(version) if version == SCHEMA_VERSION => Ok(Some(version)),
+        Ok(version) if version > SCHEMA_VERSION => Err(LambdaError::unsupported_schema(
+            key,
+            format!("schema {} is newer than the supported schema {}", version, SCHEMA_VERSION),
+        )),
+        _ => Err(LambdaError::unsupported_schema(key, format!("unknown schema marker {:?}", first_line))),
+    }
+}
+
+pub fn parse_csv(key: &str, bytes: &[u8]) -> Result<Vec<SentimentRecord>, LambdaError> {
+    let csv_content = std::str::from_utf8(bytes)
+        .map_err(|e| LambdaError::InternalError(format!("Invalid UTF-8 sequence in {}: {}", key, e)))?;
+    let csv_content = csv_content.trim_start_matches('\u{feff}');
+    if csv_content.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let marker = schema_marker(key, csv_content)?;
+    // 版本标记是 '#' 开头的注释行，csv 读取时跳过，行号保持不变
+    let mut rdr = ReaderBuilder::new().comment(Some(b'#')).trim(csv::Trim::Headers).from_reader(csv_content.as_bytes());
+    let header: Vec<String> = rdr
+        .headers()
+        .map_err(|e| LambdaError::csv_parse(key, e))?
+        .iter()
+        .map(String::from)
+        .collect();
+
+    let version = match marker {
+        Some(version) if header == header_for(version) => version,
+        Some(version) => {
+            return Err(LambdaError::unsupported_schema(
+                key,
+                format!("header {:?} does not match schema {} ({:?})", header, version, header_for(version)),
+            ))
+        }
+        None => match HEADERS.iter().position(|expected| header == *expected) {
+            Some(index) => index as u32 + 1,
+            None => {
+                return Err(LambdaError::unsupported_schema(
+                    key,
+                    format!("unknown header {:?}, expected {:?}", header, header_for(SCHEMA_VERSION)),
+                ))
+            }
+        },
+    };
+    if marker.is_none() {
+        tracing::info!(key, version, "Reading legacy CSV layout, it will be upgraded on the next write");
+    }
+
+    let records: Result<Vec<SentimentRecord>, csv::Error> = rdr.deserialize().collect();
+    records.map_err(|e| LambdaError::csv_parse(key, e))
+}
+
+pub async fn read_and_parse_csv(storage: &dyn Storage, key: &str) -> Result<Vec<SentimentRecord>, LambdaError> {
+    let object = storage.get(key).await?;
+    parse_csv(key, &object.data)
+}
+
+pub fn to_records(counts: &Counts) -> Vec<SentimentRecord> {
+    counts.iter().map(|(key, aggregate)| SentimentRecord::new(key, aggregate)).collect()
+}
+
+pub fn fold_records(records: Vec<SentimentRecord>) -> Counts {
+    records.into_iter().fold(HashMap::new(), |mut acc, rec| {
+        let (key, aggregate) = rec.into_entry();
+        acc.entry(key).or_default().merge(&aggregate); // 注意这里需要进行累加
+        acc
+    })
+}
+
+// 把增量合并进已有的计数
+pub fn merge_counts(counts: &mut Counts, increments: &Counts) {
+    for (key, aggregate) in increments {
+        counts.entry(key.clone()).or_default().merge(aggregate);
+    }
+}
+
+// 只取总计部分：情感 -> 统计
+pub fn all_time(counts: &Counts) -> BTreeMap<String, Aggregate> {
+    let mut totals: BTreeMap<String, Aggregate> = BTreeMap::new();
+    for (key, aggregate) in counts {
+        if key.bucket == ALL_TIME_BUCKET {
+            totals.entry(key.sentiment.clone()).or_default().merge(aggregate);
+        }
+    }
+    totals
+}
+
+// 只取总计部分：情感 -> 计数
+pub fn all_time_totals(counts: &Counts) -> BTreeMap<String, i32> {
+    all_time(counts)
+        .into_iter()
+        .map(|(sentiment, aggregate)| (sentiment, aggregate.count))
+        .collect()
+}
+
+// 固定的行顺序：总计在前，然后按时间桶排序；同一个桶内按 Polarity 的顺序
+fn row_order(key: &CountKey) -> (bool, &str, usize, &str) {
+    let polarity_rank = [Polarity::Positive, Polarity::Negative]
+        .iter()
+        .position(|polarity| polarity.as_str() == key.sentiment)
+        .unwrap_or(usize::MAX);
+    (key.bucket != ALL_TIME_BUCKET, key.bucket.as_str(), polarity_rank, key.sentiment.as_str())
+}
+
+pub fn serialize_counts(counts: &Counts) -> Result<Vec<u8>, LambdaError> {
+    let mut wtr = format!("{}{}\n", SCHEMA_MARKER, SCHEMA_VERSION).into_bytes();
+
+    let mut entries: Vec<(&CountKey, &Aggregate)> = counts.iter().collect();
+    entries.sort_by(|(a, _), (b, _)| row_order(a).cmp(&row_order(b)));
+
+    {
+        // 表头单独写入，没有记录时也保留表头
+        let mut csv_writer = WriterBuilder::new().has_headers(false).from_writer(&mut wtr);
+        csv_writer.write_record(header_for(SCHEMA_VERSION))
+            .map_err(|e| LambdaError::InternalError(format!("Failed to write CSV: {}", e)))?;
+        for (key, aggregate) in entries {
+            csv_writer.serialize(SentimentRecord::new(key, aggregate))
+                .map_err(|e| LambdaError::InternalError(format!("Failed to write CSV: {}", e)))?;
+        }
+
+        // Crucial step: Flush the writer
+        csv_writer.flush().map_err(|e| LambdaError::InternalError(format!("Failed to flush CSV: {}", e)))?;
+    }
+
+    Ok(wtr)
+}
+
+// 一条分析结果在总计、当天和当前小时的桶里各计一次
+pub fn add_increment(counts: &mut Counts, sentiment: &str, score: Option<f64>, at: DateTime<Utc>) {
+    for bucket in [all_time_bucket(), Granularity::Day.bucket(at), Granularity::Hour.bucket(at)] {
+        let key = CountKey { bucket, sentiment: sentiment.to_string() };
+        counts.entry(key).or_default().observe(score);
+    }
+}
+
+pub fn sentiment_increments(sentiments: &[Sentiment], at: DateTime<Utc>) -> Counts {
+    let mut increments = Counts::new();
+    for sentiment in sentiments {
+        add_increment(&mut increments, Polarity::from(&sentiment.polarity).as_str(), Some(sentiment.score), at);
+    }
+    increments
+}
+
+// 取出 [from, to] 范围内该粒度的桶：bucket -> (情感 -> 计数)，按时间排序；
+// bucket 字符串按字典序排列即为时间顺序
+pub fn range_counts(counts: &Counts, granularity: Granularity, from: &str, to: &str) -> BTreeMap<String, BTreeMap<String, Aggregate>> {
+    let mut buckets: BTreeMap<String, BTreeMap<String, Aggregate>> = BTreeMap::new();
+    for (key, aggregate) in counts {
+        let bucket = key.bucket.as_str();
+        if Granularity::of_bucket(bucket) == Some(granularity) && bucket >= from && bucket <= to {
+            buckets.entry(key.bucket.clone()).or_default().entry(key.sentiment.clone()).or_default().merge(aggregate);
+        }
+    }
+    buckets
+}
+
+// 删除超过保留期的小时桶，0 表示永久保留
+pub fn prune_hourly(counts: &mut Counts, retention_days: u32, now: DateTime<Utc>) {
+    if retention_days == 0 {
+        return;
+    }
+    let cutoff = Granularity::Hour.bucket(now - ChronoDuration::days(i64::from(retention_days)));
+    counts.retain(|key, _| Granularity::of_bucket(&key.bucket) != Some(Granularity::Hour) || key.bucket >= cutoff);
+}
+
+// namespace 的计数放在 "<key 去掉 .csv>-namespaces/<namespace>.csv"，不带 namespace 时使用 key 本身
+pub fn aggregate_key(key: &str, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => format!("{}-namespaces/{}.csv", key.strip_suffix(".csv").unwrap_or(key), namespace),
+        None => key.to_string(),
+    }
+}
+
+// 全局计数和所有允许的 namespace 的计数对象
+pub fn all_aggregate_keys(config: &AppConfig) -> Vec<String> {
+    std::iter::once(None)
+        .chain(config.namespaces.allowed.iter().map(|namespace| Some(namespace.as_str())))
+        .map(|namespace| aggregate_key(&config.storage.key, namespace))
+        .collect()
+}
+
+// 分片对象放在 "<key 去掉 .csv>-shards/" 下
+pub fn shard_prefix(key: &str) -> String {
+    format!("{}-shards/", key.strip_suffix(".csv").unwrap_or(key))
+}
+
+pub fn shard_key(key: &str, index: u32) -> String {
+    format!("{}{:03}.csv", shard_prefix(key), index)
+}
+
+// 随机选择一个分片，分散并发写入
+fn pick_shard(shards: u32) -> u32 {
+    let random = RandomState::new().build_hasher().finish();
+    (random % u64::from(shards)) as u32
+}
+
+// 新环境中还没有计数对象时的初始值：所有已知情感在总计中为 0
+pub fn initial_counts() -> Counts {
+    [Polarity::Positive, Polarity::Negative]
+        .iter()
+        .map(|polarity| {
+            let key = CountKey { bucket: all_time_bucket(), sentiment: polarity.as_str().to_string() };
+            (key, Aggregate::default())
+        })
+        .collect()
+}
+
+// 只有对象不存在才从初始值创建；桶不存在 (NoSuchBucket) 或无法区分的 404 直接返回错误
+fn is_missing(err: &LambdaError) -> bool {
+    err.storage_kind() == Some(StorageErrorKind::NoSuchKey)
+}
+
+// 读取 - 累加 - 条件写入；ETag 变了说明有并发写入，重新读取后重试
+async fn add_counts(storage: &dyn Storage, key: &str, increments: &Counts, config: &AppConfig) -> Result<(), LambdaError> {
+    let max_attempts = config.storage.max_write_attempts;
+    for attempt in 1..=max_attempts {
+        let (mut counts, condition) = match storage.get(key).await {
+            Ok(object) => (fold_records(parse_csv(key, &object.data)?), WriteCondition::from_etag(object.etag)),
+            // 对象还不存在时从初始值开始创建；If-None-Match 保证只有一个写入者创建成功，
+            // 其他写入者得到 PreconditionFailed 后重新读取
+            Err(e) if is_missing(&e) => {
+                tracing::info!(key, "Initializing missing aggregate object");
+                (initial_counts(), WriteCondition::IfAbsent)
+            }
+            Err(e) => return Err(e),
+        };
+
+        merge_counts(&mut counts, increments);
+        prune_hourly(&mut counts, config.aggregate.hourly_retention_days, Utc::now());
+        let data = serialize_counts(&counts)?;
+
+        match storage.put(key, data, condition).await {
+            Ok(()) => return Ok(()),
+            Err(e) if e.storage_kind() == Some(StorageErrorKind::PreconditionFailed) => {
+                tracing::warn!(key, attempt, "Concurrent update detected, retrying");
+                if attempt < max_attempts {
+                    tokio::time::sleep(Duration::from_millis(20 * u64::from(attempt))).await;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(LambdaError::WriteConflict {
+        key: key.to_string(),
+        attempts: max_attempts,
+    })
+}
+
+// 开启分片时写入随机的一个分片，否则直接更新计数对象 (aggregate_key)
+pub async fn update_sentiment_count(storage: &dyn Storage, config: &AppConfig, key: &str, increments: &Counts) -> Result<(), LambdaError> {
+    let shards = config.storage.shards;
+    if shards > 0 {
+        let shard = shard_key(key, pick_shard(shards));
+        add_counts(storage, &shard, increments, config).await
+    } else {
+        add_counts(storage, key, increments, config).await
+    }
+}
+
+// 计数对象加上它所有分片的记录；关闭分片后残留的分片也会被计入。
+// 计数对象还没有创建时按初始值（全为 0）处理
+pub async fn read_merged_records(storage: &dyn Storage, key: &str) -> Result<Vec<SentimentRecord>, LambdaError> {
+    let mut records = match read_and_parse_csv(storage, key).await {
+        Ok(records) => records,
+        Err(e) if is_missing(&e) => to_records(&initial_counts()),
+        Err(e) => return Err(e),
+    };
+    for shard in storage.list(&shard_prefix(key)).await? {
+        records.extend(read_and_parse_csv(storage, &shard).await?);
+    }
+    Ok(records)
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct CompactionReport {
+    // 检查过的计数对象：全局和每个 namespace
+    pub keys: Vec<String>,
+    pub shards_compacted: usize,
+    pub shards_skipped: usize,
+    // 合并的总计部分：情感 -> 计数
+    pub merged: BTreeMap<String, i32>,
+}
+
+// 把分片中的计数合并回各自的计数对象
+pub async fn compact_shards(storage: &dyn Storage, config: &AppConfig) -> Result<CompactionReport, LambdaError> {
+    let mut report = CompactionReport::default();
+    for key in all_aggregate_keys(config) {
+        compact_key(storage, config, &key, &mut report).await?;
+        report.keys.push(key);
+    }
+    Ok(report)
+}
+
+async fn compact_key(storage: &dyn Storage, config: &AppConfig, key: &str, report: &mut CompactionReport) -> Result<(), LambdaError> {
+    for shard in storage.list(&shard_prefix(key)).await? {
+        let object = storage.get(&shard).await?;
+        let counts = fold_records(parse_csv(&shard, &object.data)?);
+        if counts.values().all(Aggregate::is_empty) {
+            continue;
+        }
+
+        // 先用 If-Match 把分片清零来"认领"这些计数；失败说明分片刚被写入，下次再合并
+        let zeroed: Counts = counts.keys().map(|count_key| (count_key.clone(), Aggregate::default())).collect();
+        match storage.put(&shard, serialize_counts(&zeroed)?, WriteCondition::from_etag(object.etag)).await {
+            Ok(()) => {}
+            Err(e) if e.storage_kind() == Some(StorageErrorKind::PreconditionFailed) => {
+                report.shards_skipped += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        if let Err(e) = add_counts(storage, key, &counts, config).await {
+            // 合并失败时把计数加回分片，避免丢失
+            if let Err(restore_err) = add_counts(storage, &shard, &counts, config).await {
+                tracing::error!(shard = shard.as_str(), ?counts, error = %restore_err, "Failed to restore shard counts");
+            }
+            return Err(e);
+        }
+
+        for (sentiment, count) in all_time_totals(&counts) {
+            *report.merged.entry(sentiment).or_insert(0) += count;
+        }
+        report.shards_compacted += 1;
+    }
+
+    Ok(())
+}
+
+// 某个计数对象在某一时刻的 ETag；etag 为 None 表示对象当时不存在
+pub struct CountsSnapshot {
+    pub key: String,
+    etag: Option<String>,
+}
+
+// 记录计数对象和它全部分片的 ETag；重建时要在读取事件日志之前调用
+pub async fn snapshot_counts(storage: &dyn Storage, key: &str) -> Result<Vec<CountsSnapshot>, LambdaError> {
+    let mut keys = vec![key.to_string()];
+    keys.extend(storage.list(&shard_prefix(key)).await?);
+    let mut snapshots = Vec::with_capacity(keys.len());
+    for key in keys {
+        let etag = match storage.get(&key).await {
+            Ok(object) => object.etag,
+            Err(e) if is_missing(&e) => None,
+            Err(e) => return Err(e),
+        };
+        snapshots.push(CountsSnapshot { key, etag });
+    }
+    Ok(snapshots)
+}
+
+// 只有对象仍是快照时的版本才写入。事件先于计数写入，快照之后的计数更新无法区分
+// 它的事件是否已被重建计入，所以不合并，直接报告冲突：重建要求先停止写入
+async fn replace_if_unchanged(storage: &dyn Storage, snapshot: &CountsSnapshot, counts: &Counts) -> Result<(), LambdaError> {
+    let condition = snapshot.etag.clone().map_or(WriteCondition::IfAbsent, WriteCondition::IfMatch);
+    match storage.put(&snapshot.key, serialize_counts(counts)?, condition).await {
+        Err(e) if e.storage_kind() == Some(StorageErrorKind::PreconditionFailed) => {
+            tracing::error!(key = snapshot.key.as_str(), "Counts changed during rebuild; stop writes and run it again");
+            Err(LambdaError::WriteConflict {
+                key: snapshot.key.clone(),
+                attempts: 1,
+            })
+        }
+        result => result,
+    }
+}
+
+// 用事件日志重新计算的计数整体替换计数对象 (snapshots[0])，并清空快照中的分片，避免重复计数。
+// 事件日志是唯一的来源：快照之后有写入时返回 WriteConflict，不覆盖新的计数
+pub async fn replace_counts(storage: &dyn Storage, snapshots: &[CountsSnapshot], counts: &Counts) -> Result<usize, LambdaError> {
+    let Some((main, shards)) = snapshots.split_first() else {
+        return Ok(0);
+    };
+    replace_if_unchanged(storage, main, counts).await?;
+    for shard in shards {
+        replace_if_unchanged(storage, shard, &Counts::new()).await?;
+    }
+    Ok(shards.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::FaultStorage;
+    use crate::storage::MemoryStorage;
+
+    fn sharded_config(shards: u32) -> AppConfig {
+        let mut config = AppConfig::default();
+        config.storage.shards = shards;
+        config
+    }
+
+    async fn seed(storage: &MemoryStorage, key: &str, data: &str) {
+        storage.put(key, data.as_bytes().to_vec(), WriteCondition::Always).await.unwrap();
+    }
+
+    fn key(bucket: &str, sentiment: &str) -> CountKey {
+        CountKey { bucket: bucket.to_string(), sentiment: sentiment.to_string() }
+    }
+
+    fn count(count: i32) -> Aggregate {
+        Aggregate { count, ..Aggregate::default() }
+    }
+
+    fn increments(positive: i32, negative: i32) -> Counts {
+        [(key("all", "Positive"), count(positive)), (key("all", "Negative"), count(negative))].into_iter().collect()
+    }
+
+    #[test]
+    fn test_shard_key() {
+        assert_eq!(shard_key("sentiment.csv", 7), "sentiment-shards/007.csv");
+    }
+
+    #[test]
+    fn test_aggregate_key() {
+        assert_eq!(aggregate_key("sentiment.csv", None), "sentiment.csv");
+        assert_eq!(aggregate_key("sentiment.csv", Some("shop")), "sentiment-namespaces/shop.csv");
+        assert_eq!(shard_key(&aggregate_key("sentiment.csv", Some("shop")), 1), "sentiment-namespaces/shop-shards/001.csv");
+    }
+
+    #[tokio::test]
+    async fn test_write_conflict_after_max_attempts() {
+        // 每次条件写入都返回 PreconditionFailed，模拟一直有并发写入者
+        let storage = FaultStorage::new();
+        storage.fail("put", StorageErrorKind::PreconditionFailed);
+        seed(&storage.inner, "sentiment.csv", "Sentiment,Count\nPositive,1\n").await;
+        let mut config = AppConfig::default();
+        config.storage.max_write_attempts = 3;
+
+        let err = update_sentiment_count(&storage, &config, "sentiment.csv", &increments(1, 0)).await.unwrap_err();
+        assert!(matches!(&err, LambdaError::WriteConflict { attempts: 3, .. }), "{:?}", err);
+        assert_eq!(err.status_code(), lambda_http::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(storage.puts(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_are_counted_and_compacted_separately() {
+        let storage = MemoryStorage::new();
+        let mut config = sharded_config(2);
+        config.namespaces.allowed = vec!["shop".to_string()];
+        let shop = aggregate_key("sentiment.csv", Some("shop"));
+
+        update_sentiment_count(&storage, &config, "sentiment.csv", &increments(1, 0)).await.unwrap();
+        update_sentiment_count(&storage, &config, &shop, &increments(0, 3)).await.unwrap();
+
+        let report = compact_shards(&storage, &config).await.unwrap();
+        assert_eq!(report.keys, ["sentiment.csv", "sentiment-namespaces/shop.csv"]);
+        let global = all_time_totals(&fold_records(read_merged_records(&storage, "sentiment.csv").await.unwrap()));
+        let scoped = all_time_totals(&fold_records(read_and_parse_csv(&storage, &shop).await.unwrap()));
+        assert_eq!((global["Positive"], global["Negative"]), (1, 0));
+        assert_eq!((scoped["Positive"], scoped["Negative"]), (0, 3));
+    }
+
+    #[tokio::test]
+    async fn test_sharded_updates_merge_and_compact() {
+        let storage = MemoryStorage::new();
+        let config = sharded_config(4);
+        seed(&storage, "sentiment.csv", "Sentiment,Count\nPositive,1\nNegative,0\n").await;
+
+        for _ in 0..10 {
+            update_sentiment_count(&storage, &config, "sentiment.csv", &increments(1, 2)).await.unwrap();
+        }
+
+        let merged = all_time_totals(&fold_records(read_merged_records(&storage, "sentiment.csv").await.unwrap()));
+        assert_eq!(merged["Positive"], 11);
+        assert_eq!(merged["Negative"], 20);
+
+        let report = compact_shards(&storage, &config).await.unwrap();
+        assert_eq!(report.merged["Positive"], 10);
+        let canonical = all_time_totals(&fold_records(read_and_parse_csv(&storage, "sentiment.csv").await.unwrap()));
+        assert_eq!(canonical["Positive"], 11);
+        assert_eq!(canonical["Negative"], 20);
+
+        // 合并后总数不变
+        let merged = all_time_totals(&fold_records(read_merged_records(&storage, "sentiment.csv").await.unwrap()));
+        assert_eq!(merged["Positive"], 11);
+        assert_eq!(merged["Negative"], 20);
+    }
+
+    #[tokio::test]
+    async fn test_missing_aggregate_is_initialized() {
+        let storage = MemoryStorage::new();
+        let config = AppConfig::default();
+
+        let empty = all_time_totals(&fold_records(read_merged_records(&storage, "sentiment.csv").await.unwrap()));
+        assert_eq!(empty["Positive"], 0);
+        assert_eq!(empty["Negative"], 0);
+
+        for _ in 0..5 {
+            update_sentiment_count(&storage, &config, "sentiment.csv", &increments(1, 0)).await.unwrap();
+        }
+        let totals = all_time_totals(&fold_records(read_and_parse_csv(&storage, "sentiment.csv").await.unwrap()));
+        assert_eq!(totals["Positive"], 5);
+        assert_eq!(totals["Negative"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_initialization_race_retries_against_existing_object() {
+        let storage = MemoryStorage::new();
+        let config = AppConfig::default();
+        // 另一个写入者已经创建了对象：IfAbsent 写入失败，说明初始化不会覆盖已有计数
+        seed(&storage, "sentiment.csv", "Sentiment,Count\nPositive,7\n").await;
+        let err = storage.put("sentiment.csv", serialize_counts(&initial_counts()).unwrap(), WriteCondition::IfAbsent).await.unwrap_err();
+        assert_eq!(err.storage_kind(), Some(StorageErrorKind::PreconditionFailed));
+
+        update_sentiment_count(&storage, &config, "sentiment.csv", &increments(1, 0)).await.unwrap();
+        let totals = all_time_totals(&fold_records(read_and_parse_csv(&storage, "sentiment.csv").await.unwrap()));
+        assert_eq!(totals["Positive"], 8);
+    }
+
+    #[tokio::test]
+    async fn test_replace_counts_resets_shards() {
+        let storage = MemoryStorage::new();
+        let config = sharded_config(2);
+        seed(&storage, "sentiment.csv", "Sentiment,Count\nPositive,100\n").await;
+        update_sentiment_count(&storage, &config, "sentiment.csv", &increments(50, 0)).await.unwrap();
+
+        let snapshots = snapshot_counts(&storage, "sentiment.csv").await.unwrap();
+        assert_eq!(snapshots.len(), 2);
+        let shards = replace_counts(&storage, &snapshots, &increments(3, 0)).await.unwrap();
+        assert_eq!(shards, 1);
+        let merged = all_time_totals(&fold_records(read_merged_records(&storage, "sentiment.csv").await.unwrap()));
+        assert_eq!((merged["Positive"], merged["Negative"]), (3, 0));
+    }
+
+    #[test]
+    fn test_time_buckets() {
+        let at = NaiveDate::from_ymd_opt(2024, 4, 1).unwrap().and_hms_opt(13, 5, 0).unwrap().and_utc();
+        assert_eq!(Granularity::Day.bucket(at), "2024-04-01");
+        assert_eq!(Granularity::Hour.bucket(at), "2024-04-01T13");
+        assert_eq!(Granularity::Hour.normalize("2024-04-01", true).as_deref(), Some("2024-04-01T23"));
+        assert_eq!(Granularity::Day.normalize("2024-04-01T13", false).as_deref(), Some("2024-04-01"));
+        assert_eq!(Granularity::Day.normalize("yesterday", false), None);
+    }
+
+    #[test]
+    fn test_range_counts() {
+        let counts: Counts = [
+            (key("all", "Positive"), count(9)),
+            (key("2024-03-31", "Positive"), count(1)),
+            (key("2024-04-01", "Positive"), count(2)),
+            (key("2024-04-01", "Negative"), count(1)),
+            (key("2024-04-02", "Negative"), count(4)),
+            (key("2024-04-01T13", "Positive"), count(2)),
+        ]
+        .into_iter()
+        .collect();
+        let days = range_counts(&counts, Granularity::Day, "2024-04-01", "2024-04-02");
+        assert_eq!(days.keys().collect::<Vec<_>>(), ["2024-04-01", "2024-04-02"]);
+        assert_eq!(days["2024-04-01"]["Positive"].count, 2);
+        assert_eq!(days["2024-04-01"]["Negative"].count, 1);
+
+        let hours = range_counts(&counts, Granularity::Hour, "2024-04-01T00", "2024-04-01T23");
+        assert_eq!(hours.len(), 1);
+        assert_eq!(hours["2024-04-01T13"]["Positive"].count, 2);
+    }
+
+    #[test]
+    fn test_legacy_csv_reads_as_all_time() {
+        let counts = fold_records(parse_csv("sentiment.csv", b"Sentiment,Count\nPositive,3\nNegative,1\n").unwrap());
+        assert_eq!(counts[&key("all", "Positive")].count, 3);
+        assert_eq!(counts[&key("all", "Positive")].mean(), None);
+    }
+
+    #[test]
+    fn test_score_statistics_round_trip() {
+        let at = Utc::now();
+        let mut counts = Counts::new();
+        for score in [0.55, 0.95, 0.99] {
+            add_increment(&mut counts, "Positive", Some(score), at);
+        }
+        // v2 文件中只有计数的旧记录：合并后计数增加，分数统计只来自新记录
+        merge_counts(&mut counts, &[(key("all", "Positive"), count(2))].into_iter().collect());
+
+        let data = serialize_counts(&counts).unwrap();
+        let parsed = fold_records(parse_csv("sentiment.csv", &data).unwrap());
+        let positive = &parsed[&key("all", "Positive")];
+        assert_eq!(positive.count, 5);
+        assert_eq!(positive.scored, 3);
+        assert_eq!(positive.score_min, Some(0.55));
+        assert_eq!(positive.score_max, Some(0.99));
+        assert!((positive.mean().unwrap() - 0.83).abs() < 1e-9);
+        assert!(positive.std_dev().unwrap() > 0.0);
+        assert_eq!(positive.histogram[5], 1);
+        assert_eq!(positive.histogram[9], 2);
+        assert_eq!(parsed[&key(&Granularity::Day.bucket(at), "Positive")].scored, 3);
+    }
+
+    #[test]
+    fn test_serialize_is_deterministic_and_versioned() {
+        let mut counts = Counts::new();
+        for (bucket, sentiment) in [("2024-04-02", "Negative"), ("all", "Negative"), ("2024-04-01", "Positive"), ("all", "Positive"), ("2024-04-01", "Negative")] {
+            counts.insert(key(bucket, sentiment), count(1));
+        }
+        let data = String::from_utf8(serialize_counts(&counts).unwrap()).unwrap();
+        let rows: Vec<String> = data.lines().map(|line| line.split(',').take(2).collect::<Vec<_>>().join(",")).collect();
+        assert_eq!(
+            rows,
+            [
+                "# sentiment-aggregate schema=3",
+                "Bucket,Sentiment",
+                "all,Positive",
+                "all,Negative",
+                "2024-04-01,Positive",
+                "2024-04-01,Negative",
+                "2024-04-02,Negative",
+            ]
+        );
+        assert_eq!(fold_records(parse_csv("sentiment.csv", data.as_bytes()).unwrap()), counts);
+        // 空计数仍然写出版本标记和表头
+        assert!(parse_csv("sentiment.csv", &serialize_counts(&Counts::new()).unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_layouts_are_rejected() {
+        let unknown_header = b"Polarity,Total\nPositive,3\n";
+        let newer_schema = b"# sentiment-aggregate schema=99\nBucket,Sentiment\n";
+        let wrong_header = b"# sentiment-aggregate schema=3\nBucket,Sentiment,Count\nall,Positive,1\n";
+        for data in [&unknown_header[..], &newer_schema[..], &wrong_header[..]] {
+            assert!(matches!(parse_csv("sentiment.csv", data), Err(LambdaError::UnsupportedSchema { .. })));
+        }
+        // 已知的旧表头可以读取
+        let v2 = fold_records(parse_csv("sentiment.csv", b"Bucket,Sentiment,Count\n2024-04-01,Negative,2\n").unwrap());
+        assert_eq!(v2[&key("2024-04-01", "Negative")].count, 2);
+    }
+
+    #[test]
+    fn test_invalid_histogram_is_a_parse_error() {
+        let data = b"Bucket,Sentiment,Count,Scored,ScoreSum,ScoreSqSum,ScoreMin,ScoreMax,Histogram\nall,Positive,1,1,0.9,0.81,0.9,0.9,1 2\n";
+        assert!(matches!(parse_csv("sentiment.csv", data), Err(LambdaError::CsvParse { .. })));
+    }
+
+    #[test]
+    fn test_prune_hourly_keeps_recent_and_daily() {
+        let now = NaiveDate::from_ymd_opt(2024, 4, 10).unwrap().and_hms_opt(0, 0, 0).unwrap().and_utc();
+        let mut counts: Counts = [
+            (key("all", "Positive"), count(1)),
+            (key("2024-01-01", "Positive"), count(1)),
+            (key("2024-01-01T05", "Positive"), count(1)),
+            (key("2024-04-09T05", "Positive"), count(1)),
+        ]
+        .into_iter()
+        .collect();
+        prune_hourly(&mut counts, 7, now);
+        assert_eq!(counts.len(), 3);
+        assert!(!counts.contains_key(&key("2024-01-01T05", "Positive")));
+    }
+}
@@ -0,0 +1,202 @@
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::aggregate::{self, Counts};
+use crate::config::BufferConfig;
+
+// 一次请求尚未写入的计数增量
+pub struct PendingIncrement {
+    pub key: String,
+    pub increments: Counts,
+    // 对应事件写入日志的时间；事件没有写入日志时为 None，重建不会计入
+    pub logged_at: Option<DateTime<Utc>>,
+}
+
+// 合并后的一组增量：同一个计数对象，事件都写入了日志或都没有写入
+pub struct MergedIncrements {
+    pub key: String,
+    pub increments: Counts,
+    // 组内最晚的 logged_at：早于最近一次重建时整组都已被重建计入
+    pub logged_at: Option<DateTime<Utc>>,
+}
+
+// 按计数对象合并，事件已写入日志和没有写入的分开，放入 outbox 后仍能判断是否被重建计入
+pub fn merge(pending: Vec<PendingIncrement>) -> Vec<MergedIncrements> {
+    let mut merged: HashMap<(String, bool), MergedIncrements> = HashMap::new();
+    for increment in pending {
+        let entry = merged
+            .entry((increment.key.clone(), increment.logged_at.is_some()))
+            .or_insert_with(|| MergedIncrements {
+                key: increment.key,
+                increments: Counts::new(),
+                logged_at: None,
+            });
+        aggregate::merge_counts(&mut entry.increments, &increment.increments);
+        entry.logged_at = entry.logged_at.max(increment.logged_at);
+    }
+    merged.into_values().collect()
+}
+
+#[derive(Default)]
+struct BufferState {
+    // 按请求保存而不是提前合并：写入前要按 logged_at 去掉已被重建计入的部分
+    pending: Vec<PendingIncrement>,
+    // 最早一条未写入增量的时间
+    since: Option<Instant>,
+}
+
+// 热实例中共享的写缓冲：多次请求的增量合并后只做一次读-改-写
+pub struct WriteBuffer {
+    max_requests: usize,
+    max_age: Duration,
+    state: Mutex<BufferState>,
+}
+
+impl WriteBuffer {
+    pub fn new(config: &BufferConfig) -> Self {
+        WriteBuffer {
+            max_requests: config.max_requests,
+            max_age: Duration::from_millis(config.max_age_ms),
+            state: Mutex::new(BufferState::default()),
+        }
+    }
+
+    // 加入一次请求的增量；达到请求数或时间阈值时取出全部待写增量，由调用方负责写入
+    pub fn add(&self, increment: PendingIncrement) -> Option<Vec<PendingIncrement>> {
+        let mut state = self.state.lock().expect("write buffer lock poisoned");
+        state.pending.push(increment);
+        let since = *state.since.get_or_insert_with(Instant::now);
+        if state.pending.len() >= self.max_requests || since.elapsed() >= self.max_age {
+            Some(Self::take_locked(&mut state))
+        } else {
+            None
+        }
+    }
+
+    // 最早的增量已超过 max_age 时取出全部待写增量；Lambda 在两次调用之间冻结实例，
+    // 所以在每次调用开始时检查，而不是依赖定时器
+    pub fn take_expired(&self) -> Option<Vec<PendingIncrement>> {
+        let mut state = self.state.lock().expect("write buffer lock poisoned");
+        match state.since {
+            Some(since) if since.elapsed() >= self.max_age => Some(Self::take_locked(&mut state)),
+            _ => None,
+        }
+    }
+
+    // 取出全部待写增量 (关闭时)；取出后缓冲为空，同一份增量不会被写两次
+    pub fn take(&self) -> Vec<PendingIncrement> {
+        let mut state = self.state.lock().expect("write buffer lock poisoned");
+        Self::take_locked(&mut state)
+    }
+
+    fn take_locked(state: &mut BufferState) -> Vec<PendingIncrement> {
+        state.since = None;
+        std::mem::take(&mut state.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::storage::testing::FaultStorage;
+    use crate::storage::Storage;
+    use std::sync::Arc;
+
+    fn increment(key: &str, sentiment: &str) -> PendingIncrement {
+        let mut increments = Counts::new();
+        aggregate::add_increment(&mut increments, sentiment, Some(0.8), Utc::now());
+        PendingIncrement { key: key.to_string(), increments, logged_at: Some(Utc::now()) }
+    }
+
+    fn totals(merged: &[MergedIncrements], key: &str, logged: bool) -> std::collections::BTreeMap<String, i32> {
+        let group = merged.iter().find(|group| group.key == key && group.logged_at.is_some() == logged).unwrap();
+        aggregate::all_time_totals(&group.increments)
+    }
+
+    #[test]
+    fn test_flushes_every_max_requests() {
+        let buffer = WriteBuffer::new(&BufferConfig { max_requests: 3, max_age_ms: 60_000, ..BufferConfig::default() });
+        assert!(buffer.add(increment("sentiment.csv", "Positive")).is_none());
+        assert!(buffer.add(increment("sentiment-namespaces/shop.csv", "Negative")).is_none());
+        let merged = merge(buffer.add(increment("sentiment.csv", "Positive")).unwrap());
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(totals(&merged, "sentiment.csv", true)["Positive"], 2);
+        assert_eq!(totals(&merged, "sentiment-namespaces/shop.csv", true)["Negative"], 1);
+        assert!(buffer.take().is_empty());
+    }
+
+    #[test]
+    fn test_merge_keeps_unlogged_increments_apart() {
+        let first = increment("sentiment.csv", "Positive");
+        let second = increment("sentiment.csv", "Positive");
+        let latest = second.logged_at;
+        let unlogged = PendingIncrement { logged_at: None, ..increment("sentiment.csv", "Negative") };
+
+        let merged = merge(vec![first, unlogged, second]);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(totals(&merged, "sentiment.csv", true)["Positive"], 2);
+        assert_eq!(totals(&merged, "sentiment.csv", false)["Negative"], 1);
+        let logged = merged.iter().find(|group| group.logged_at.is_some()).unwrap();
+        assert_eq!(logged.logged_at, latest);
+    }
+
+    #[test]
+    fn test_flushes_after_max_age() {
+        let buffer = WriteBuffer::new(&BufferConfig { max_requests: 100, max_age_ms: 0, ..BufferConfig::default() });
+        assert!(buffer.add(increment("sentiment.csv", "Positive")).is_some());
+    }
+
+    #[test]
+    fn test_take_expired_only_after_max_age() {
+        let buffer = WriteBuffer::new(&BufferConfig { max_requests: 100, max_age_ms: 20, ..BufferConfig::default() });
+        assert!(buffer.take_expired().is_none());
+        assert!(buffer.add(increment("sentiment.csv", "Positive")).is_none());
+        assert!(buffer.take_expired().is_none());
+
+        std::thread::sleep(Duration::from_millis(30));
+        let merged = merge(buffer.take_expired().unwrap());
+        assert_eq!(totals(&merged, "sentiment.csv", true)["Positive"], 1);
+        assert!(buffer.take_expired().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_flushes_lose_no_increments() {
+        // get 之后让出执行权，并发的读-改-写会在 get 和 put 之间交错
+        let storage = Arc::new(FaultStorage::new().yielding());
+        let mut config = AppConfig::default();
+        config.storage.max_write_attempts = 50;
+        let config = Arc::new(config);
+        let buffer = Arc::new(WriteBuffer::new(&BufferConfig { max_requests: 7, max_age_ms: 60_000, ..BufferConfig::default() }));
+
+        let tasks: Vec<_> = (0..50)
+            .map(|index| {
+                let (storage, config, buffer) = (Arc::clone(&storage), Arc::clone(&config), Arc::clone(&buffer));
+                tokio::spawn(async move {
+                    let sentiment = if index % 2 == 0 { "Positive" } else { "Negative" };
+                    if let Some(pending) = buffer.add(increment("sentiment.csv", sentiment)) {
+                        for group in merge(pending) {
+                            aggregate::update_sentiment_count(storage.as_ref(), &config, &group.key, &group.increments).await.unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // 关闭时写入剩余部分
+        for group in merge(buffer.take()) {
+            aggregate::update_sentiment_count(storage.as_ref(), &config, &group.key, &group.increments).await.unwrap();
+        }
+
+        let data = storage.get("sentiment.csv").await.unwrap().data;
+        let counts = aggregate::fold_records(aggregate::parse_csv("sentiment.csv", &data).unwrap());
+        let totals = aggregate::all_time_totals(&counts);
+        assert_eq!(totals["Positive"], 25);
+        assert_eq!(totals["Negative"], 25);
+    }
+}
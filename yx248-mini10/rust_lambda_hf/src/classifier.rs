@@ -0,0 +1,195 @@
+use async_trait::async_trait;
+use rust_bert::pipelines::common::ModelType as RustBertModelType;
+use rust_bert::pipelines::sentiment::{Sentiment, SentimentConfig, SentimentModel, SentimentPolarity};
+use rust_bert::resources::{LocalResource, ResourceProvider};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::config::{ModelBackend, ModelConfig, ModelFiles, ModelType};
+use crate::error::LambdaError;
+
+// 情感分析的推理后端
+#[async_trait]
+pub trait SentimentClassifier: Send + Sync {
+    fn backend(&self) -> &'static str;
+
+    // 每条输入返回一个结果，顺序与输入一致
+    async fn predict(&self, texts: &[&str]) -> Result<Vec<Sentiment>, LambdaError>;
+}
+
+pub struct RustBertClassifier {
+    model: Mutex<SentimentModel>,
+}
+
+fn rust_bert_model_type(model_type: ModelType) -> RustBertModelType {
+    match model_type {
+        ModelType::DistilBert => RustBertModelType::DistilBert,
+        ModelType::Bert => RustBertModelType::Bert,
+        ModelType::Roberta => RustBertModelType::Roberta,
+        ModelType::XlmRoberta => RustBertModelType::XLMRoberta,
+        ModelType::Albert => RustBertModelType::Albert,
+        ModelType::Deberta => RustBertModelType::Deberta,
+        ModelType::Electra => RustBertModelType::Electra,
+        ModelType::MobileBert => RustBertModelType::MobileBert,
+    }
+}
+
+fn local_resource(path: &std::path::Path) -> Box<dyn ResourceProvider + Send> {
+    Box::new(LocalResource { local_path: path.to_path_buf() })
+}
+
+// 加载前检查所有文件，一次报告全部缺少的文件，而不是在 libtorch 中失败
+pub fn check_model_files(files: &ModelFiles) -> Result<(), LambdaError> {
+    let missing: Vec<String> = [Some(&files.config), Some(&files.vocab), files.merges.as_ref(), Some(&files.weights)]
+        .into_iter()
+        .flatten()
+        .filter(|path| !path.is_file())
+        .map(|path| path.display().to_string())
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(LambdaError::InternalError(format!("Model files not found: {}", missing.join(", "))))
+    }
+}
+
+impl RustBertClassifier {
+    // 加载权重较慢，只在冷启动时调用一次
+    pub fn load(config: &ModelConfig) -> Result<Self, LambdaError> {
+        // 缓存目录中的默认模型同样按本地文件加载，不需要在运行时设置 RUSTBERT_CACHE
+        let sentiment_config = match config.local_files().or_else(|| config.cached_files()) {
+            Some(files) => {
+                check_model_files(&files)?;
+                tracing::info!(model = config.name, weights = %files.weights.display(), "Loading model from local files");
+                SentimentConfig {
+                    model_type: rust_bert_model_type(config.model_type),
+                    model_resource: local_resource(&files.weights),
+                    config_resource: local_resource(&files.config),
+                    vocab_resource: local_resource(&files.vocab),
+                    merges_resource: files.merges.as_deref().map(local_resource),
+                    lower_case: config.lower_case(),
+                    ..Default::default()
+                }
+            }
+            // 默认模型从 Hugging Face 下载
+            None => SentimentConfig::default(),
+        };
+        let model = SentimentModel::new(sentiment_config)
+            .map_err(|e| LambdaError::InternalError(format!("Failed to load model {}: {}", config.name, e)))?;
+        Ok(RustBertClassifier { model: Mutex::new(model) })
+    }
+}
+
+#[async_trait]
+impl SentimentClassifier for RustBertClassifier {
+    fn backend(&self) -> &'static str {
+        "rust-bert"
+    }
+
+    async fn predict(&self, texts: &[&str]) -> Result<Vec<Sentiment>, LambdaError> {
+        // 获取Mutex的锁，同一时间只有一个请求使用模型
+        let model = self.model.lock().await;
+        Ok(model.predict(texts))
+    }
+}
+
+const POSITIVE_WORDS: &[&str] = &["good", "great", "love", "excellent", "happy", "best", "nice", "awesome"];
+const NEGATIVE_WORDS: &[&str] = &["bad", "terrible", "hate", "awful", "sad", "worst", "poor", "horrible"];
+
+// 按关键词计数给出确定的结果，不加载任何模型文件
+pub struct MockClassifier;
+
+impl MockClassifier {
+    fn classify(text: &str) -> Sentiment {
+        let (mut positive, mut negative) = (0i32, 0i32);
+        for word in text.split(|c: char| !c.is_alphanumeric()).map(str::to_lowercase) {
+            if POSITIVE_WORDS.contains(&word.as_str()) {
+                positive += 1;
+            } else if NEGATIVE_WORDS.contains(&word.as_str()) {
+                negative += 1;
+            }
+        }
+        // 没有关键词时为 Positive 0.5，关键词差距越大分数越接近 1
+        let polarity = if negative > positive { SentimentPolarity::Negative } else { SentimentPolarity::Positive };
+        let score = 0.5 + 0.5 * (positive - negative).abs() as f64 / (positive + negative + 1) as f64;
+        Sentiment { polarity, score }
+    }
+}
+
+#[async_trait]
+impl SentimentClassifier for MockClassifier {
+    fn backend(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn predict(&self, texts: &[&str]) -> Result<Vec<Sentiment>, LambdaError> {
+        Ok(texts.iter().map(|text| MockClassifier::classify(text)).collect())
+    }
+}
+
+// 根据配置创建推理后端；rust-bert 会阻塞加载模型，调用方需要放在 block_in_place 中
+pub fn from_config(config: &ModelConfig) -> Result<Arc<dyn SentimentClassifier>, LambdaError> {
+    match config.backend {
+        ModelBackend::RustBert => Ok(Arc::new(RustBertClassifier::load(config)?)),
+        ModelBackend::Mock => Ok(Arc::new(MockClassifier)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_classifier_is_deterministic() {
+        let classifier = MockClassifier;
+        let texts = ["I love this, it is great", "Terrible service", "The sky is blue"];
+        let first = classifier.predict(&texts).await.unwrap();
+        let second = classifier.predict(&texts).await.unwrap();
+
+        assert_eq!(first.len(), 3);
+        assert_eq!(first[0].polarity, SentimentPolarity::Positive);
+        assert!(first[0].score > 0.8);
+        assert_eq!(first[1].polarity, SentimentPolarity::Negative);
+        assert_eq!(first[2].polarity, SentimentPolarity::Positive);
+        assert_eq!(first[2].score, 0.5);
+        for (a, b) in first.iter().zip(&second) {
+            assert_eq!(a.polarity, b.polarity);
+            assert_eq!(a.score, b.score);
+        }
+    }
+
+    #[test]
+    fn test_missing_model_files_are_reported() {
+        let dir = std::env::temp_dir().join(format!("rust_lambda_hf_model_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("config.json"), b"{}").unwrap();
+        let config = ModelConfig {
+            dir: Some(dir.clone()),
+            ..ModelConfig::default()
+        };
+
+        let err = RustBertClassifier::load(&config).err().unwrap().to_string();
+        assert!(err.contains("vocab.txt"), "{}", err);
+        assert!(err.contains("rust_model.ot"), "{}", err);
+        assert!(!err.contains("config.json"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_cached_files_are_reported() {
+        let dir = std::env::temp_dir().join(format!("rust_lambda_hf_cache_{}", std::process::id()));
+        let config = ModelConfig {
+            cache_dir: Some(dir.clone()),
+            ..ModelConfig::default()
+        };
+        let files = config.cached_files().unwrap();
+        std::fs::create_dir_all(files.vocab.parent().unwrap()).unwrap();
+        std::fs::write(&files.vocab, b"[PAD]\n").unwrap();
+
+        let err = RustBertClassifier::load(&config).err().unwrap().to_string();
+        assert!(err.contains("distilbert-sst2/config/config.json"), "{}", err);
+        assert!(err.contains("distilbert-sst2/model/rust_model.ot"), "{}", err);
+        assert!(!err.contains("vocab.txt"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
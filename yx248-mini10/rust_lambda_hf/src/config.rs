@@ -0,0 +1,905 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::MODEL_ID;
+
+// 配置文件路径（.toml 或 .json），其余环境变量会覆盖文件中的值
+pub const CONFIG_FILE_ENV: &str = "SENTIMENT_CONFIG_FILE";
+// Lambda 运行环境中总是设置的变量
+const LAMBDA_RUNTIME_ENV: &str = "AWS_LAMBDA_RUNTIME_API";
+
+// 目前支持的模型
+const SUPPORTED_MODELS: &[&str] = &[MODEL_ID];
+// mock 后端不加载模型；响应、事件和导出中使用这个 id，不会被当成真实模型的结果
+pub const MOCK_MODEL_ID: &str = "mock";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {}: {}", .path.display(), .source)]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Failed to parse config file {}: {}", .path.display(), .message)]
+    Parse { path: PathBuf, message: String },
+    #[error("Unsupported config file format {} (expected .toml or .json)", .0.display())]
+    UnsupportedFormat(PathBuf),
+    #[error("Invalid value {value:?} for environment variable {var}")]
+    InvalidEnv { var: &'static str, value: String },
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    S3,
+    Local,
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "s3" => Ok(StorageBackend::S3),
+            "local" => Ok(StorageBackend::Local),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    // 只用于 s3 后端
+    pub bucket: String,
+    pub key: String,
+    // 只用于 local 后端
+    pub local_dir: PathBuf,
+    // 计数更新遇到并发冲突 (ETag 不匹配) 时最多尝试的次数
+    pub max_write_attempts: u32,
+    // 计数分片数量，0 表示所有写入都直接更新 key
+    pub shards: u32,
+    pub retry: RetryConfig,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::S3,
+            bucket: "sentiments-data".to_string(),
+            key: "sentiment.csv".to_string(),
+            local_dir: PathBuf::from("data"),
+            max_write_attempts: 5,
+            shards: 0,
+            retry: RetryConfig::default(),
+        }
+    }
+}
+
+// 单次存储调用遇到限流、超时等临时错误时的重试和熔断设置
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    // 包括第一次调用，1 表示不重试
+    pub max_attempts: u32,
+    // 指数退避的初始和最大等待时间，实际等待在 [0, 退避值] 之间随机
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // 一次存储调用 (含全部重试) 的总时限
+    pub deadline_ms: u64,
+    // 连续失败多少次后熔断，0 表示不熔断
+    pub breaker_threshold: u32,
+    // 熔断后多久放行一次试探请求
+    pub breaker_cooldown_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 50,
+            max_delay_ms: 1_000,
+            deadline_ms: 5_000,
+            breaker_threshold: 5,
+            breaker_cooldown_ms: 30_000,
+        }
+    }
+}
+
+// 推理后端；mock 不需要模型文件，用于测试和没有网络的 CI
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModelBackend {
+    RustBert,
+    Mock,
+}
+
+impl FromStr for ModelBackend {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "rust-bert" => Ok(ModelBackend::RustBert),
+            "mock" => Ok(ModelBackend::Mock),
+            _ => Err(()),
+        }
+    }
+}
+
+// rust-bert 支持的序列分类模型结构
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelType {
+    DistilBert,
+    Bert,
+    Roberta,
+    XlmRoberta,
+    Albert,
+    Deberta,
+    Electra,
+    MobileBert,
+}
+
+impl ModelType {
+    // BPE 分词的模型还需要 merges 文件
+    pub fn needs_merges(&self) -> bool {
+        matches!(self, ModelType::Roberta | ModelType::Deberta)
+    }
+
+    // model.dir 下默认的词表文件名
+    pub fn vocab_file_name(&self) -> &'static str {
+        match self {
+            ModelType::Roberta | ModelType::Deberta => "vocab.json",
+            ModelType::XlmRoberta => "sentencepiece.bpe.model",
+            ModelType::Albert => "spiece.model",
+            _ => "vocab.txt",
+        }
+    }
+}
+
+impl FromStr for ModelType {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "distilbert" => Ok(ModelType::DistilBert),
+            "bert" => Ok(ModelType::Bert),
+            "roberta" => Ok(ModelType::Roberta),
+            "xlmroberta" => Ok(ModelType::XlmRoberta),
+            "albert" => Ok(ModelType::Albert),
+            "deberta" => Ok(ModelType::Deberta),
+            "electra" => Ok(ModelType::Electra),
+            "mobilebert" => Ok(ModelType::MobileBert),
+            _ => Err(()),
+        }
+    }
+}
+
+// 从本地加载模型时使用的文件
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelFiles {
+    pub config: PathBuf,
+    pub vocab: PathBuf,
+    pub merges: Option<PathBuf>,
+    pub weights: PathBuf,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    pub backend: ModelBackend,
+    // 响应和事件中的模型名；使用本地文件时可以是任意名字
+    pub name: String,
+    pub model_type: ModelType,
+    // 本地模型目录，包含 config.json、词表 (merges.txt) 和 rust_model.ot；设置后不再下载模型
+    pub dir: Option<PathBuf>,
+    // 单独指定的文件，优先于 dir 下的默认文件名
+    pub config_file: Option<PathBuf>,
+    pub vocab_file: Option<PathBuf>,
+    pub merges_file: Option<PathBuf>,
+    pub weights_file: Option<PathBuf>,
+    // 分词前是否转小写，必须和模型的词表一致 (uncased 模型为 true)。
+    // 从 model_type 无法判断 (bert 有 cased 和 uncased 版本)，使用本地文件时必须显式设置；默认模型为 true
+    pub lower_case: Option<bool>,
+    // 预先下载好默认模型的 rust-bert 缓存目录 (构建镜像时用 RUSTBERT_CACHE 下载)；设置后不再下载模型
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            backend: ModelBackend::RustBert,
+            name: MODEL_ID.to_string(),
+            model_type: ModelType::DistilBert,
+            dir: None,
+            config_file: None,
+            vocab_file: None,
+            merges_file: None,
+            weights_file: None,
+            lower_case: None,
+            cache_dir: None,
+        }
+    }
+}
+
+impl ModelConfig {
+    // 实际产生结果的模型：mock 后端忽略 model.name
+    pub fn id(&self) -> &str {
+        match self.backend {
+            ModelBackend::RustBert => &self.name,
+            ModelBackend::Mock => MOCK_MODEL_ID,
+        }
+    }
+
+    pub fn lower_case(&self) -> bool {
+        self.lower_case.unwrap_or(true)
+    }
+
+    pub fn uses_local_files(&self) -> bool {
+        self.dir.is_some()
+            || self.config_file.is_some()
+            || self.vocab_file.is_some()
+            || self.merges_file.is_some()
+            || self.weights_file.is_some()
+    }
+
+    // 解析本地文件路径；没有配置本地文件时返回 None，缺少的文件在 validate 中报告
+    pub fn local_files(&self) -> Option<ModelFiles> {
+        if !self.uses_local_files() {
+            return None;
+        }
+        let resolve = |file: &Option<PathBuf>, default_name: &str| {
+            file.clone().or_else(|| self.dir.as_ref().map(|dir| dir.join(default_name)))
+        };
+        let merges = if self.model_type.needs_merges() { resolve(&self.merges_file, "merges.txt") } else { self.merges_file.clone() };
+        Some(ModelFiles {
+            config: resolve(&self.config_file, "config.json")?,
+            vocab: resolve(&self.vocab_file, self.model_type.vocab_file_name())?,
+            merges,
+            weights: resolve(&self.weights_file, "rust_model.ot")?,
+        })
+    }
+
+    // 默认模型 (DistilBERT SST-2) 在 rust-bert 缓存目录中的路径：{cache_dir}/{资源名}/{文件名}
+    pub fn cached_files(&self) -> Option<ModelFiles> {
+        let dir = self.cache_dir.as_ref()?.join("distilbert-sst2");
+        Some(ModelFiles {
+            config: dir.join("config").join("config.json"),
+            vocab: dir.join("vocab").join("vocab.txt"),
+            merges: None,
+            weights: dir.join("model").join("rust_model.ot"),
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThresholdConfig {
+    // score 低于该值的结果标记为 low_confidence
+    pub low_confidence: f64,
+}
+
+impl Default for ThresholdConfig {
+    fn default() -> Self {
+        ThresholdConfig { low_confidence: 0.6 }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // 单条文本的最大字符数
+    pub max_text_length: usize,
+    // sentiment_batch 一次最多的文本数
+    pub max_batch_size: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_text_length: 10_000,
+            max_batch_size: 1_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregateConfig {
+    // 小时桶保留的天数，0 = 永久保留；天桶和总计不会被清理
+    pub hourly_retention_days: u32,
+}
+
+impl Default for AggregateConfig {
+    fn default() -> Self {
+        AggregateConfig { hourly_retention_days: 30 }
+    }
+}
+
+// 事件日志中如何保存原始文本
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventTextMode {
+    None,
+    Hash,
+    Full,
+}
+
+impl FromStr for EventTextMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Ok(EventTextMode::None),
+            "hash" => Ok(EventTextMode::Hash),
+            "full" => Ok(EventTextMode::Full),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventLogConfig {
+    pub enabled: bool,
+    // 事件对象的前缀，按日期分区：<prefix>date=YYYY-MM-DD/...jsonl
+    pub prefix: String,
+    pub text: EventTextMode,
+}
+
+impl Default for EventLogConfig {
+    fn default() -> Self {
+        EventLogConfig {
+            enabled: false,
+            prefix: "events/".to_string(),
+            text: EventTextMode::Hash,
+        }
+    }
+}
+
+// 按产品 / 渠道 / 租户分开计数；请求中的 namespace 必须在 allowed 中，不带 namespace 的请求计入全局计数
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamespaceConfig {
+    pub allowed: Vec<String>,
+}
+
+impl NamespaceConfig {
+    pub fn is_allowed(&self, namespace: &str) -> bool {
+        self.allowed.iter().any(|allowed| allowed == namespace)
+    }
+}
+
+// namespace 会成为对象 key 的一部分，只允许小写字母、数字、'-' 和 '_'
+pub fn is_valid_namespace(namespace: &str) -> bool {
+    (1..=64).contains(&namespace.len())
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+// 热实例中先在内存中累积计数增量，每 max_requests 次请求或 max_age_ms 后合并写入一次
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferConfig {
+    // 1 表示每次请求都直接写入
+    pub max_requests: usize,
+    // 每次调用开始时检查，超过这个时间的缓冲增量先写入
+    pub max_age_ms: u64,
+    // 已注册 Lambda extension，实例关闭前会收到 SIGTERM；否则 Lambda 中不允许 max_requests > 1
+    pub shutdown_extension: bool,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            max_requests: 1,
+            max_age_ms: 5_000,
+            shutdown_extension: false,
+        }
+    }
+}
+
+// 计数更新失败时，把增量写入本地目录，之后的调用中重试
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboxConfig {
+    pub enabled: bool,
+    // Lambda 中只有 /tmp 可写；同一个实例的后续调用可以看到之前写入的文件
+    pub dir: PathBuf,
+    // 超过上限后不再写入，避免占满 /tmp
+    pub max_entries: usize,
+    // 每次调用最多重试的条数，限制额外延迟
+    pub drain_batch: usize,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            enabled: true,
+            dir: PathBuf::from("/tmp/sentiment-outbox"),
+            max_entries: 10_000,
+            drain_batch: 20,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    // Parquet 文件的前缀：<prefix>date=YYYY-MM-DD/events.parquet
+    pub prefix: String,
+    pub row_group_size: usize,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig {
+            prefix: "exports/".to_string(),
+            row_group_size: 100_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // API Gateway 部署时请求路径带有的前缀 (例如 stage 名)，路由前去掉；"" 表示没有前缀
+    pub base_path: String,
+    // /admin/* 请求必须在 x-admin-token 头中带上这个共享密钥；不输出到序列化结果中
+    #[serde(skip_serializing)]
+    pub admin_token: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            base_path: "/mini10-rust-hf-lambda".to_string(),
+            admin_token: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    pub batch: bool,
+    pub stats: bool,
+    // /admin/* 维护接口（例如分片合并）
+    pub admin: bool,
+    // 默认输出旧格式的 result 字符串，旧客户端迁移完成后关闭
+    pub legacy_result: bool,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        FeatureConfig {
+            batch: true,
+            stats: true,
+            admin: false,
+            legacy_result: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub model: ModelConfig,
+    pub thresholds: ThresholdConfig,
+    pub limits: LimitsConfig,
+    pub aggregate: AggregateConfig,
+    pub namespaces: NamespaceConfig,
+    pub events: EventLogConfig,
+    pub buffer: BufferConfig,
+    pub outbox: OutboxConfig,
+    pub export: ExportConfig,
+    pub features: FeatureConfig,
+}
+
+fn parse_env<T: FromStr>(var: &'static str, value: String) -> Result<T, ConfigError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| ConfigError::InvalidEnv { var, value })
+}
+
+fn parse_bool_env(var: &'static str, value: String) -> Result<bool, ConfigError> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(ConfigError::InvalidEnv { var, value }),
+    }
+}
+
+impl AppConfig {
+    // 启动时调用：读取配置文件（如果有）、应用环境变量覆盖并校验
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var(CONFIG_FILE_ENV) {
+            Ok(path) if !path.is_empty() => AppConfig::from_file(Path::new(&path))?,
+            _ => AppConfig::default(),
+        };
+        config.apply_env(|var| std::env::var(var).ok())?;
+        config.validate()?;
+        if std::env::var_os(LAMBDA_RUNTIME_ENV).is_some() {
+            config.validate_lambda()?;
+        }
+        Ok(config)
+    }
+
+    // 只在 Lambda 中运行时检查：没有 extension 时 Lambda 关闭实例不会发送 SIGTERM，缓冲中的增量会丢失
+    pub fn validate_lambda(&self) -> Result<(), ConfigError> {
+        if self.buffer.max_requests > 1 && !self.buffer.shutdown_extension {
+            return Err(ConfigError::Invalid(
+                "buffer.max_requests > 1 requires a Lambda extension so SIGTERM is delivered on shutdown; \
+                 register one and set buffer.shutdown_extension = true"
+                    .into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let parse_error = |message: String| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message,
+        };
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| parse_error(e.to_string())),
+            Some("json") => serde_json::from_str(&content).map_err(|e| parse_error(e.to_string())),
+            _ => Err(ConfigError::UnsupportedFormat(path.to_path_buf())),
+        }
+    }
+
+    // 环境变量优先于配置文件
+    pub fn apply_env<F>(&mut self, lookup: F) -> Result<(), ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(value) = lookup("SENTIMENT_STORAGE_BACKEND") {
+            self.storage.backend = parse_env("SENTIMENT_STORAGE_BACKEND", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_LOCAL_DIR") {
+            self.storage.local_dir = PathBuf::from(value);
+        }
+        if let Some(value) = lookup("SENTIMENT_BASE_PATH") {
+            self.server.base_path = value;
+        }
+        if let Some(value) = lookup("SENTIMENT_ADMIN_TOKEN") {
+            self.server.admin_token = Some(value);
+        }
+        if let Some(value) = lookup("SENTIMENT_BUCKET") {
+            self.storage.bucket = value;
+        }
+        if let Some(value) = lookup("SENTIMENT_KEY") {
+            self.storage.key = value;
+        }
+        if let Some(value) = lookup("SENTIMENT_MAX_WRITE_ATTEMPTS") {
+            self.storage.max_write_attempts = parse_env("SENTIMENT_MAX_WRITE_ATTEMPTS", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_SHARDS") {
+            self.storage.shards = parse_env("SENTIMENT_SHARDS", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_RETRY_MAX_ATTEMPTS") {
+            self.storage.retry.max_attempts = parse_env("SENTIMENT_RETRY_MAX_ATTEMPTS", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_RETRY_DEADLINE_MS") {
+            self.storage.retry.deadline_ms = parse_env("SENTIMENT_RETRY_DEADLINE_MS", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_BREAKER_THRESHOLD") {
+            self.storage.retry.breaker_threshold = parse_env("SENTIMENT_BREAKER_THRESHOLD", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_MODEL_BACKEND") {
+            self.model.backend = parse_env("SENTIMENT_MODEL_BACKEND", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_MODEL_TYPE") {
+            self.model.model_type = parse_env("SENTIMENT_MODEL_TYPE", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_MODEL_DIR") {
+            self.model.dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = lookup("SENTIMENT_MODEL_LOWER_CASE") {
+            self.model.lower_case = Some(parse_bool_env("SENTIMENT_MODEL_LOWER_CASE", value)?);
+        }
+        if let Some(value) = lookup("SENTIMENT_MODEL_CACHE_DIR") {
+            self.model.cache_dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = lookup("SENTIMENT_MODEL") {
+            self.model.name = value;
+        }
+        if let Some(value) = lookup("SENTIMENT_LOW_CONFIDENCE") {
+            self.thresholds.low_confidence = parse_env("SENTIMENT_LOW_CONFIDENCE", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_MAX_TEXT_LENGTH") {
+            self.limits.max_text_length = parse_env("SENTIMENT_MAX_TEXT_LENGTH", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_MAX_BATCH_SIZE") {
+            self.limits.max_batch_size = parse_env("SENTIMENT_MAX_BATCH_SIZE", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_HOURLY_RETENTION_DAYS") {
+            self.aggregate.hourly_retention_days = parse_env("SENTIMENT_HOURLY_RETENTION_DAYS", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_NAMESPACES") {
+            self.namespaces.allowed = value
+                .split(',')
+                .map(str::trim)
+                .filter(|namespace| !namespace.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some(value) = lookup("SENTIMENT_EVENT_LOG") {
+            self.events.enabled = parse_bool_env("SENTIMENT_EVENT_LOG", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_EVENT_PREFIX") {
+            self.events.prefix = value;
+        }
+        if let Some(value) = lookup("SENTIMENT_EVENT_TEXT") {
+            self.events.text = parse_env("SENTIMENT_EVENT_TEXT", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_BUFFER_MAX_REQUESTS") {
+            self.buffer.max_requests = parse_env("SENTIMENT_BUFFER_MAX_REQUESTS", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_BUFFER_MAX_AGE_MS") {
+            self.buffer.max_age_ms = parse_env("SENTIMENT_BUFFER_MAX_AGE_MS", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_BUFFER_SHUTDOWN_EXTENSION") {
+            self.buffer.shutdown_extension = parse_bool_env("SENTIMENT_BUFFER_SHUTDOWN_EXTENSION", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_OUTBOX_ENABLED") {
+            self.outbox.enabled = parse_bool_env("SENTIMENT_OUTBOX_ENABLED", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_OUTBOX_DIR") {
+            self.outbox.dir = PathBuf::from(value);
+        }
+        if let Some(value) = lookup("SENTIMENT_EXPORT_PREFIX") {
+            self.export.prefix = value;
+        }
+        if let Some(value) = lookup("SENTIMENT_EXPORT_ROW_GROUP_SIZE") {
+            self.export.row_group_size = parse_env("SENTIMENT_EXPORT_ROW_GROUP_SIZE", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_ENABLE_BATCH") {
+            self.features.batch = parse_bool_env("SENTIMENT_ENABLE_BATCH", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_ENABLE_STATS") {
+            self.features.stats = parse_bool_env("SENTIMENT_ENABLE_STATS", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_ENABLE_ADMIN") {
+            self.features.admin = parse_bool_env("SENTIMENT_ENABLE_ADMIN", value)?;
+        }
+        if let Some(value) = lookup("SENTIMENT_LEGACY_RESULT") {
+            self.features.legacy_result = parse_bool_env("SENTIMENT_LEGACY_RESULT", value)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let base_path = &self.server.base_path;
+        if !base_path.is_empty() && (!base_path.starts_with('/') || base_path.ends_with('/')) {
+            return Err(ConfigError::Invalid(format!("server.base_path {:?} must start with '/' and not end with '/'", base_path)));
+        }
+        // 管理接口会删除和覆盖数据，不允许在没有密钥的情况下开启
+        if self.features.admin && self.server.admin_token.as_deref().unwrap_or("").is_empty() {
+            return Err(ConfigError::Invalid("features.admin requires server.admin_token (SENTIMENT_ADMIN_TOKEN)".into()));
+        }
+        let bucket = &self.storage.bucket;
+        let valid_bucket = (3..=63).contains(&bucket.len())
+            && bucket
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.');
+        if self.storage.backend == StorageBackend::S3 && !valid_bucket {
+            return Err(ConfigError::Invalid(format!("storage.bucket {:?} is not a valid S3 bucket name", bucket)));
+        }
+        if self.storage.backend == StorageBackend::Local && self.storage.local_dir.as_os_str().is_empty() {
+            return Err(ConfigError::Invalid("storage.local_dir must be set for the local backend".into()));
+        }
+        if self.storage.key.is_empty() || self.storage.key.ends_with('/') {
+            return Err(ConfigError::Invalid(format!("storage.key {:?} must name an object", self.storage.key)));
+        }
+        if self.storage.max_write_attempts == 0 {
+            return Err(ConfigError::Invalid("storage.max_write_attempts must be at least 1".into()));
+        }
+        let retry = &self.storage.retry;
+        if retry.max_attempts == 0 || retry.deadline_ms == 0 {
+            return Err(ConfigError::Invalid("storage.retry.max_attempts and storage.retry.deadline_ms must be greater than 0".into()));
+        }
+        if retry.base_delay_ms > retry.max_delay_ms {
+            return Err(ConfigError::Invalid("storage.retry.base_delay_ms must not exceed storage.retry.max_delay_ms".into()));
+        }
+        if let Some(namespace) = self.namespaces.allowed.iter().find(|namespace| !is_valid_namespace(namespace)) {
+            return Err(ConfigError::Invalid(format!(
+                "namespaces.allowed entry {:?} must be 1-64 characters of a-z, 0-9, '-' or '_'",
+                namespace
+            )));
+        }
+        if self.events.enabled && (self.events.prefix.is_empty() || !self.events.prefix.ends_with('/')) {
+            return Err(ConfigError::Invalid("events.prefix must be a non-empty prefix ending with '/'".into()));
+        }
+        if self.buffer.max_requests == 0 {
+            return Err(ConfigError::Invalid("buffer.max_requests must be at least 1".into()));
+        }
+        if self.outbox.enabled && (self.outbox.dir.as_os_str().is_empty() || self.outbox.drain_batch == 0) {
+            return Err(ConfigError::Invalid("outbox.dir must be set and outbox.drain_batch must be greater than 0".into()));
+        }
+        if self.export.prefix.is_empty() || !self.export.prefix.ends_with('/') || self.export.prefix == self.events.prefix {
+            return Err(ConfigError::Invalid("export.prefix must end with '/' and differ from events.prefix".into()));
+        }
+        if self.export.row_group_size == 0 {
+            return Err(ConfigError::Invalid("export.row_group_size must be greater than 0".into()));
+        }
+        if self.storage.shards > 1000 {
+            return Err(ConfigError::Invalid("storage.shards must be at most 1000".into()));
+        }
+        if self.model.name.is_empty() {
+            return Err(ConfigError::Invalid("model.name must not be empty".into()));
+        }
+        // 不使用本地文件时只能下载默认模型
+        if self.model.backend == ModelBackend::RustBert
+            && !self.model.uses_local_files()
+            && (!SUPPORTED_MODELS.contains(&self.model.name.as_str()) || self.model.model_type != ModelType::DistilBert)
+        {
+            return Err(ConfigError::Invalid(format!(
+                "model.name {:?} is not supported without local model files (supported: {}); set model.dir for other models",
+                self.model.name,
+                SUPPORTED_MODELS.join(", ")
+            )));
+        }
+        if self.model.backend == ModelBackend::RustBert && !self.model.uses_local_files() && self.model.lower_case == Some(false) {
+            return Err(ConfigError::Invalid("model.lower_case must be true for the default uncased model".into()));
+        }
+        if self.model.uses_local_files() {
+            if self.model.lower_case.is_none() {
+                return Err(ConfigError::Invalid(
+                    "model.lower_case must be set for local model files (true for uncased vocabularies, false for cased ones)".into(),
+                ));
+            }
+            match self.model.local_files() {
+                None => {
+                    return Err(ConfigError::Invalid(
+                        "model.config_file, model.vocab_file and model.weights_file must all be set when model.dir is not".into(),
+                    ));
+                }
+                Some(files) if files.merges.is_none() && self.model.model_type.needs_merges() => {
+                    return Err(ConfigError::Invalid("model.merges_file must be set for this model_type".into()));
+                }
+                Some(_) => {}
+            }
+        }
+        if !(0.0..=1.0).contains(&self.thresholds.low_confidence) {
+            return Err(ConfigError::Invalid("thresholds.low_confidence must be between 0 and 1".into()));
+        }
+        if self.limits.max_text_length == 0 || self.limits.max_batch_size == 0 {
+            return Err(ConfigError::Invalid("limits must be greater than 0".into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_defaults_are_valid() {
+        let config = AppConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.storage.bucket, "sentiments-data");
+        assert_eq!(config.storage.key, "sentiment.csv");
+    }
+
+    #[test]
+    fn test_parse_toml_with_partial_sections() {
+        let config: AppConfig = toml::from_str("[storage]\nbucket = \"sentiments-staging\"\n[limits]\nmax_batch_size = 50\n").unwrap();
+        assert_eq!(config.storage.bucket, "sentiments-staging");
+        assert_eq!(config.storage.key, "sentiment.csv");
+        assert_eq!(config.limits.max_batch_size, 50);
+        assert!(toml::from_str::<AppConfig>("[storage]\nbuckt = \"typo\"\n").is_err());
+
+        let config: AppConfig = toml::from_str("[storage.retry]\nmax_attempts = 1\n").unwrap();
+        assert_eq!(config.storage.retry.max_attempts, 1);
+        assert_eq!(config.storage.retry.deadline_ms, 5_000);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env: HashMap<&str, &str> = [
+            ("SENTIMENT_STORAGE_BACKEND", "local"),
+            ("SENTIMENT_BUCKET", "sentiments-prod"),
+            ("SENTIMENT_ENABLE_STATS", "false"),
+            ("SENTIMENT_MAX_TEXT_LENGTH", "200"),
+            ("SENTIMENT_NAMESPACES", "shop, support,"),
+            ("SENTIMENT_MODEL_LOWER_CASE", "false"),
+        ]
+        .into_iter()
+        .collect();
+        let mut config = AppConfig::default();
+        config.apply_env(|var| env.get(var).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.storage.backend, StorageBackend::Local);
+        assert_eq!(config.storage.bucket, "sentiments-prod");
+        assert!(!config.features.stats);
+        assert_eq!(config.limits.max_text_length, 200);
+        assert_eq!(config.namespaces.allowed, ["shop", "support"]);
+        assert_eq!(config.model.lower_case, Some(false));
+
+        let err = config.apply_env(|var| (var == "SENTIMENT_MAX_BATCH_SIZE").then(|| "many".to_string()));
+        assert!(matches!(err, Err(ConfigError::InvalidEnv { var: "SENTIMENT_MAX_BATCH_SIZE", .. })));
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_values() {
+        let mut config = AppConfig::default();
+        config.storage.bucket = "Bad_Bucket".to_string();
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.thresholds.low_confidence = 1.5;
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.model.name = "gpt-2".to_string();
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.namespaces.allowed = vec!["../other".to_string()];
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.storage.retry.base_delay_ms = 5_000;
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.features.admin = true;
+        assert!(config.validate().is_err());
+        config.server.admin_token = Some("secret".to_string());
+        assert!(config.validate().is_ok());
+
+        let mut config = AppConfig::default();
+        config.buffer.max_requests = 10;
+        assert!(config.validate().is_ok());
+        assert!(config.validate_lambda().is_err());
+        config.buffer.shutdown_extension = true;
+        assert!(config.validate_lambda().is_ok());
+    }
+
+    #[test]
+    fn test_local_model_files() {
+        let mut config = AppConfig::default();
+        assert_eq!(config.model.local_files(), None);
+
+        // 其他模型必须从本地加载
+        config.model.name = "acme-reviews-roberta".to_string();
+        config.model.model_type = ModelType::Roberta;
+        assert!(config.validate().is_err());
+
+        config.model.dir = Some(PathBuf::from("/opt/model"));
+        // 本地模型必须说明词表是否区分大小写
+        assert!(config.validate().is_err());
+        config.model.lower_case = Some(false);
+        assert!(config.validate().is_ok());
+        assert!(!config.model.lower_case());
+        let files = config.model.local_files().unwrap();
+        assert_eq!(files.vocab, PathBuf::from("/opt/model/vocab.json"));
+        assert_eq!(files.merges, Some(PathBuf::from("/opt/model/merges.txt")));
+        assert_eq!(files.weights, PathBuf::from("/opt/model/rust_model.ot"));
+
+        // 没有 dir 时必须单独指定全部文件
+        config.model.dir = None;
+        config.model.weights_file = Some(PathBuf::from("/opt/weights.ot"));
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use lambda_http::http::StatusCode;
+use serde_json::json;
+use std::fmt;
+use thiserror::Error;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// 存储错误的大致分类，用于区分 NoSuchKey / AccessDenied / 限流等情况
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageErrorKind {
+    NoSuchKey,
+    // 桶不存在：不能当作对象不存在去创建初始计数
+    NoSuchBucket,
+    // HEAD 请求的 404 没有错误码，无法区分对象不存在还是桶不存在
+    NotFound,
+    AccessDenied,
+    Throttling,
+    // S3 返回 5xx (InternalError / ServiceUnavailable 等)
+    ServerError,
+    PreconditionFailed,
+    Timeout,
+    Network,
+    Service,
+    // 熔断器打开，请求没有发送到存储
+    CircuitOpen,
+    Other,
+}
+
+impl StorageErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageErrorKind::NoSuchKey => "no_such_key",
+            StorageErrorKind::NoSuchBucket => "no_such_bucket",
+            StorageErrorKind::NotFound => "not_found",
+            StorageErrorKind::AccessDenied => "access_denied",
+            StorageErrorKind::Throttling => "throttling",
+            StorageErrorKind::ServerError => "server_error",
+            StorageErrorKind::PreconditionFailed => "precondition_failed",
+            StorageErrorKind::Timeout => "timeout",
+            StorageErrorKind::Network => "network",
+            StorageErrorKind::Service => "service",
+            StorageErrorKind::CircuitOpen => "circuit_open",
+            StorageErrorKind::Other => "other",
+        }
+    }
+
+    // 限流、服务端错误、超时和网络错误是临时的，可以重试；其余错误重试也不会成功
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            StorageErrorKind::Throttling | StorageErrorKind::ServerError | StorageErrorKind::Timeout | StorageErrorKind::Network
+        )
+    }
+
+    // 根据 S3 返回的错误码分类
+    fn from_code(code: Option<&str>) -> Self {
+        match code {
+            Some("NoSuchKey") => StorageErrorKind::NoSuchKey,
+            Some("NoSuchBucket") => StorageErrorKind::NoSuchBucket,
+            Some("NotFound") => StorageErrorKind::NotFound,
+            Some("AccessDenied") | Some("Forbidden") => StorageErrorKind::AccessDenied,
+            Some("SlowDown") | Some("Throttling") | Some("ThrottlingException")
+            | Some("RequestLimitExceeded") => StorageErrorKind::Throttling,
+            Some("InternalError") | Some("ServiceUnavailable") => StorageErrorKind::ServerError,
+            Some("RequestTimeout") => StorageErrorKind::Timeout,
+            Some("PreconditionFailed") | Some("ConditionalRequestConflict") => StorageErrorKind::PreconditionFailed,
+            Some(_) => StorageErrorKind::Service,
+            None => StorageErrorKind::Other,
+        }
+    }
+
+    // 没有可识别错误码的响应按 HTTP 状态分类
+    fn from_status(status: u16) -> Self {
+        match status {
+            429 => StorageErrorKind::Throttling,
+            500..=599 => StorageErrorKind::ServerError,
+            400..=499 => StorageErrorKind::Service,
+            // 2xx 响应的 body 读取或解析失败 (例如连接中途断开)
+            _ => StorageErrorKind::Network,
+        }
+    }
+
+    pub fn classify<E>(err: &SdkError<E, HttpResponse>) -> Self
+    where
+        E: ProvideErrorMetadata,
+    {
+        match err {
+            SdkError::TimeoutError(_) => StorageErrorKind::Timeout,
+            SdkError::DispatchFailure(_) => StorageErrorKind::Network,
+            SdkError::ServiceError(context) => match StorageErrorKind::from_code(context.err().code()) {
+                StorageErrorKind::Service | StorageErrorKind::Other => StorageErrorKind::from_status(context.raw().status().as_u16()),
+                kind => kind,
+            },
+            // 收到了响应但无法解析
+            SdkError::ResponseError(context) => StorageErrorKind::from_status(context.raw().status().as_u16()),
+            _ => StorageErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for StorageErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LambdaError {
+    #[error("Invalid command")]
+    InvalidCommand,
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("Feature disabled: {0}")]
+    FeatureDisabled(String),
+    #[error("Missing or invalid admin token")]
+    Unauthorized,
+    #[error("Sentiment analysis error")]
+    SentimentError,
+    #[error("AWS S3 {operation} failed for s3://{bucket}/{key} ({kind})")]
+    S3 {
+        operation: &'static str,
+        bucket: String,
+        key: String,
+        kind: StorageErrorKind,
+        #[source]
+        source: BoxError,
+    },
+    #[error("{backend} storage {operation} failed for {key} ({kind})")]
+    Storage {
+        backend: &'static str,
+        operation: &'static str,
+        key: String,
+        kind: StorageErrorKind,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Concurrent updates to {key} still conflicting after {attempts} attempts")]
+    WriteConflict { key: String, attempts: u32 },
+    #[error("Failed to parse CSV {key} at line {line:?}, column {column:?}")]
+    CsvParse {
+        key: String,
+        line: Option<u64>,
+        column: Option<u64>,
+        #[source]
+        source: csv::Error,
+    },
+    #[error("Unsupported layout in {key}: {detail}")]
+    UnsupportedSchema { key: String, detail: String },
+    #[error("Internal Error: {0}")]
+    InternalError(String),
+}
+
+impl LambdaError {
+    // 每种错误对应的 HTTP 状态码
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            LambdaError::InvalidCommand | LambdaError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            LambdaError::FeatureDisabled(_) => StatusCode::FORBIDDEN,
+            LambdaError::Unauthorized => StatusCode::UNAUTHORIZED,
+            LambdaError::SentimentError => StatusCode::INTERNAL_SERVER_ERROR,
+            LambdaError::S3 { .. } | LambdaError::Storage { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            // 并发写入冲突重试用尽是服务端的竞争，客户端可以稍后重试
+            LambdaError::WriteConflict { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            LambdaError::CsvParse { .. } | LambdaError::UnsupportedSchema { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            LambdaError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // 稳定的错误码，客户端应根据它而不是 message 做判断
+    pub fn code(&self) -> &'static str {
+        match self {
+            LambdaError::InvalidCommand => "invalid_command",
+            LambdaError::InvalidInput(_) => "invalid_input",
+            LambdaError::FeatureDisabled(_) => "feature_disabled",
+            LambdaError::Unauthorized => "unauthorized",
+            LambdaError::SentimentError => "sentiment_error",
+            LambdaError::S3 { .. } | LambdaError::Storage { .. } => "storage_unavailable",
+            LambdaError::WriteConflict { .. } => "write_conflict",
+            LambdaError::CsvParse { .. } => "storage_corrupt",
+            LambdaError::UnsupportedSchema { .. } => "unsupported_schema",
+            LambdaError::InternalError(_) => "internal_error",
+        }
+    }
+
+    // 返回给客户端的 message：客户端错误说明原因，服务端错误只给固定文本，
+    // 存储的 bucket / key、SDK 错误等细节只写入日志
+    pub fn public_message(&self) -> String {
+        if !self.status_code().is_server_error() {
+            return self.to_string();
+        }
+        let message = match self {
+            LambdaError::SentimentError => "Sentiment analysis failed",
+            LambdaError::S3 { .. } | LambdaError::Storage { .. } => "Storage is temporarily unavailable",
+            LambdaError::WriteConflict { .. } => "Counts are being updated concurrently, retry later",
+            LambdaError::CsvParse { .. } => "Stored counts could not be parsed",
+            LambdaError::UnsupportedSchema { .. } => "Stored counts use an unsupported schema",
+            _ => "Internal error",
+        };
+        message.to_string()
+    }
+
+    // 保留 AWS SDK 的原始错误，并记录 operation / key / 错误分类
+    pub fn s3<E>(operation: &'static str, bucket: &str, key: &str, err: SdkError<E, HttpResponse>) -> Self
+    where
+        E: ProvideErrorMetadata,
+        SdkError<E, HttpResponse>: std::error::Error + Send + Sync + 'static,
+    {
+        let kind = StorageErrorKind::classify(&err);
+        tracing::warn!(operation, bucket, key, kind = kind.as_str(), error = %DisplayErrorContext(&err), "S3 request failed");
+        LambdaError::S3 {
+            operation,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            kind,
+            source: Box::new(err),
+        }
+    }
+
+    // 读取 body 等非 SdkError 的失败
+    pub fn s3_other<S>(operation: &'static str, bucket: &str, key: &str, err: S) -> Self
+    where
+        S: std::error::Error + Send + Sync + 'static,
+    {
+        tracing::warn!(operation, bucket, key, error = %err, "S3 request failed");
+        LambdaError::S3 {
+            operation,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            kind: StorageErrorKind::Network,
+            source: Box::new(err),
+        }
+    }
+
+    // 本地目录、内存等非 S3 后端的失败
+    pub fn storage<S>(backend: &'static str, operation: &'static str, key: &str, kind: StorageErrorKind, err: S) -> Self
+    where
+        S: std::error::Error + Send + Sync + 'static,
+    {
+        tracing::warn!(backend, operation, key, kind = kind.as_str(), error = %err, "Storage request failed");
+        LambdaError::Storage {
+            backend,
+            operation,
+            key: key.to_string(),
+            kind,
+            source: Box::new(err),
+        }
+    }
+
+    // S3 和其他存储后端错误的分类，其他错误返回 None
+    pub fn storage_kind(&self) -> Option<StorageErrorKind> {
+        match self {
+            LambdaError::S3 { kind, .. } | LambdaError::Storage { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+
+    // 无法识别的 CSV 版本或表头，不尝试按旧格式解析
+    pub fn unsupported_schema(key: &str, detail: String) -> Self {
+        tracing::error!(key, detail = detail.as_str(), "Unsupported CSV layout");
+        LambdaError::UnsupportedSchema {
+            key: key.to_string(),
+            detail,
+        }
+    }
+
+    pub fn csv_parse(key: &str, err: csv::Error) -> Self {
+        let line = err.position().map(|pos| pos.line());
+        let column = match err.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.field(),
+            _ => None,
+        };
+        tracing::error!(key, line, column, error = %err, "Failed to parse CSV");
+        LambdaError::CsvParse {
+            key: key.to_string(),
+            line,
+            column,
+            source: err,
+        }
+    }
+}
+
+// 统一的错误响应格式：{"error": {"code", "message", "request_id"}}
+pub fn error_body(code: &str, message: &str, request_id: &str) -> serde_json::Value {
+    json!({
+        "error": {
+            "code": code,
+            "message": message,
+            "request_id": request_id,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_codes() {
+        assert_eq!(LambdaError::InvalidCommand.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(LambdaError::InvalidInput("x".into()).status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(LambdaError::InternalError("x".into()).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(LambdaError::SentimentError.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_public_message_hides_server_error_details() {
+        let err = LambdaError::InternalError("failed to open /var/task/model.ot".into());
+        assert_eq!(err.public_message(), "Internal error");
+        let err = LambdaError::storage("s3", "get", "secret/sentiment.csv", StorageErrorKind::AccessDenied, std::io::Error::other("denied"));
+        assert_eq!(err.public_message(), "Storage is temporarily unavailable");
+        let err = LambdaError::WriteConflict { key: "secret/sentiment.csv".into(), attempts: 3 };
+        assert!(!err.public_message().contains("secret"));
+        // 客户端错误保留原因
+        let err = LambdaError::InvalidInput("text must not be empty".into());
+        assert_eq!(err.public_message(), err.to_string());
+    }
+
+    #[test]
+    fn test_error_body() {
+        let body = error_body(LambdaError::InvalidCommand.code(), "Invalid command", "req-1");
+        assert_eq!(body["error"]["code"], "invalid_command");
+        assert_eq!(body["error"]["request_id"], "req-1");
+    }
+
+    #[test]
+    fn test_s3_error_kind_from_code() {
+        assert_eq!(StorageErrorKind::from_code(Some("NoSuchKey")), StorageErrorKind::NoSuchKey);
+        assert_eq!(StorageErrorKind::from_code(Some("NoSuchBucket")), StorageErrorKind::NoSuchBucket);
+        assert_eq!(StorageErrorKind::from_code(Some("NotFound")), StorageErrorKind::NotFound);
+        assert_eq!(StorageErrorKind::from_code(Some("AccessDenied")), StorageErrorKind::AccessDenied);
+        assert_eq!(StorageErrorKind::from_code(Some("SlowDown")), StorageErrorKind::Throttling);
+        assert_eq!(StorageErrorKind::from_code(Some("PreconditionFailed")), StorageErrorKind::PreconditionFailed);
+        assert_eq!(StorageErrorKind::from_code(None), StorageErrorKind::Other);
+        assert!(StorageErrorKind::from_code(Some("RequestTimeout")).is_transient());
+        assert_eq!(StorageErrorKind::from_code(Some("InternalError")), StorageErrorKind::ServerError);
+        assert_eq!(StorageErrorKind::from_code(Some("ServiceUnavailable")), StorageErrorKind::ServerError);
+        assert!(StorageErrorKind::ServerError.is_transient());
+        assert_eq!(StorageErrorKind::from_status(502), StorageErrorKind::ServerError);
+        assert_eq!(StorageErrorKind::from_status(429), StorageErrorKind::Throttling);
+        assert_eq!(StorageErrorKind::from_status(403), StorageErrorKind::Service);
+        assert!(!StorageErrorKind::from_code(Some("AccessDenied")).is_transient());
+    }
+
+    #[test]
+    fn test_csv_parse_error_position() {
+        let data = "Sentiment,Count\nPositive,abc\n";
+        let mut rdr = csv::Reader::from_reader(data.as_bytes());
+        let err = rdr.deserialize::<(String, i32)>().next().unwrap().unwrap_err();
+        match LambdaError::csv_parse("sentiment.csv", err) {
+            LambdaError::CsvParse { line, column, .. } => {
+                assert_eq!(line, Some(2));
+                assert_eq!(column, Some(1));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+}
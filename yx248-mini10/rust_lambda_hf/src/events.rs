@@ -0,0 +1,326 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_bert::pipelines::sentiment::Sentiment;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+
+use crate::aggregate::{self, Counts, CountsSnapshot};
+use crate::config::{AppConfig, EventTextMode};
+use crate::error::{LambdaError, StorageErrorKind};
+use crate::storage::{Storage, WriteCondition};
+use crate::Polarity;
+
+// 事件日志中的一行 (JSON Lines)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SentimentEvent {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    pub polarity: String,
+    pub score: f64,
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    // 写入事件的程序版本 (CARGO_PKG_VERSION)
+    #[serde(default)]
+    pub app_version: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+fn sha256_hex(text: &str) -> String {
+    Sha256::digest(text.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// 同一次请求中所有事件共有的字段
+pub struct EventContext<'a> {
+    pub request_id: &'a str,
+    pub namespace: Option<&'a str>,
+    pub tags: &'a [String],
+}
+
+pub fn build_events(context: &EventContext, sentiments: &[Sentiment], texts: &[&str], at: DateTime<Utc>, config: &AppConfig) -> Vec<SentimentEvent> {
+    sentiments
+        .iter()
+        .zip(texts)
+        .enumerate()
+        .map(|(index, (sentiment, text))| SentimentEvent {
+            // 批量请求中用序号区分同一个 request id 下的多条事件
+            id: format!("{}-{}", context.request_id, index),
+            timestamp: at,
+            polarity: Polarity::from(&sentiment.polarity).as_str().to_string(),
+            score: sentiment.score,
+            model: config.model.id().to_string(),
+            namespace: context.namespace.map(String::from),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            tags: context.tags.to_vec(),
+            text_sha256: (config.events.text == EventTextMode::Hash).then(|| sha256_hex(text)),
+            text: (config.events.text == EventTextMode::Full).then(|| text.to_string()),
+        })
+        .collect()
+}
+
+// 某一天的事件分区：<prefix>date=YYYY-MM-DD/
+pub fn partition_prefix(prefix: &str, date: NaiveDate) -> String {
+    format!("{}date={}/", prefix, date.format("%Y-%m-%d"))
+}
+
+// 读取某一天分区下的全部事件
+pub async fn read_partition(storage: &dyn Storage, prefix: &str, date: NaiveDate) -> Result<Vec<SentimentEvent>, LambdaError> {
+    let mut events = Vec::new();
+    for key in storage.list(&partition_prefix(prefix, date)).await? {
+        if key.ends_with(".jsonl") {
+            events.extend(parse_events(&key, &storage.get(&key).await?.data)?);
+        }
+    }
+    Ok(events)
+}
+
+// 每次调用写一个新对象，对象从不覆盖：<prefix>date=YYYY-MM-DD/<时间>-<request id>-<随机>.jsonl
+pub fn event_key(prefix: &str, request_id: &str, at: DateTime<Utc>) -> String {
+    let random = RandomState::new().build_hasher().finish() as u32;
+    let request_id: String = request_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    format!(
+        "{}{}-{}-{:08x}.jsonl",
+        partition_prefix(prefix, at.date_naive()),
+        at.format("%Y%m%dT%H%M%S%.3fZ"),
+        request_id,
+        random
+    )
+}
+
+pub fn serialize_events(events: &[SentimentEvent]) -> Result<Vec<u8>, LambdaError> {
+    let mut data = Vec::new();
+    for event in events {
+        serde_json::to_writer(&mut data, event)
+            .map_err(|e| LambdaError::InternalError(format!("Failed to serialize event: {}", e)))?;
+        data.push(b'\n');
+    }
+    Ok(data)
+}
+
+pub fn parse_events(key: &str, bytes: &[u8]) -> Result<Vec<SentimentEvent>, LambdaError> {
+    let content = std::str::from_utf8(bytes)
+        .map_err(|e| LambdaError::InternalError(format!("Invalid UTF-8 sequence in {}: {}", key, e)))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| LambdaError::InternalError(format!("Invalid event in {} at line {}: {}", key, index + 1, e)))
+        })
+        .collect()
+}
+
+// 事件日志关闭时什么也不做
+pub async fn append_events(storage: &dyn Storage, config: &AppConfig, context: &EventContext<'_>, sentiments: &[Sentiment], texts: &[&str], at: DateTime<Utc>) -> Result<(), LambdaError> {
+    if !config.events.enabled {
+        return Ok(());
+    }
+    let events = build_events(context, sentiments, texts, at, config);
+    let key = event_key(&config.events.prefix, context.request_id, at);
+    storage.put(&key, serialize_events(&events)?, WriteCondition::IfAbsent).await?;
+    tracing::debug!(key, events = events.len(), "Appended sentiment events");
+    Ok(())
+}
+
+// 最近一次重建开始读取事件日志的时间：在这之前已写入日志的事件都已计入计数
+#[derive(Serialize, Deserialize, Debug)]
+struct RebuildMarker {
+    rebuilt_at: DateTime<Utc>,
+}
+
+fn rebuild_marker_key(config: &AppConfig) -> String {
+    format!("{}rebuild.json", config.events.prefix)
+}
+
+// outbox 和写缓冲中的增量如果早于这个时间且事件已写入日志，重放时要跳过，否则会计入两次
+pub async fn rebuilt_at(storage: &dyn Storage, config: &AppConfig) -> Result<Option<DateTime<Utc>>, LambdaError> {
+    let key = rebuild_marker_key(config);
+    let object = match storage.get(&key).await {
+        Ok(object) => object,
+        Err(e) if e.storage_kind() == Some(StorageErrorKind::NoSuchKey) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let marker: RebuildMarker = serde_json::from_slice(&object.data)
+        .map_err(|e| LambdaError::InternalError(format!("Invalid rebuild marker {}: {}", key, e)))?;
+    Ok(Some(marker.rebuilt_at))
+}
+
+// 事件在重建开始前已写入日志的增量已被重建计入
+pub fn counted_by_rebuild(logged_at: Option<DateTime<Utc>>, rebuilt_at: Option<DateTime<Utc>>) -> bool {
+    matches!((logged_at, rebuilt_at), (Some(logged_at), Some(rebuilt_at)) if logged_at < rebuilt_at)
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RebuildReport {
+    pub objects_read: usize,
+    pub events_read: usize,
+    // namespace 不在白名单中而跳过的事件
+    pub events_skipped: usize,
+    pub shards_reset: usize,
+    // 重建后的全局总计：情感 -> 计数
+    pub totals: BTreeMap<String, i32>,
+    // 每个 namespace 的总计
+    pub namespaces: BTreeMap<String, BTreeMap<String, i32>>,
+}
+
+// 从事件日志重新计算全部计数，并覆盖全局和各 namespace 的计数对象。
+// 每个事件对象都要读一次，只在批处理命令 (rebuild-counts) 中运行，运行前要停止写入
+pub async fn rebuild_counts(storage: &dyn Storage, config: &AppConfig) -> Result<RebuildReport, LambdaError> {
+    let mut report = RebuildReport::default();
+    // 没有事件的 namespace 也要重置为初始值。
+    // 先记录计数对象和分片的 ETag，再读取事件：期间有计数写入时替换失败，而不是重复计数
+    let mut by_namespace: HashMap<Option<String>, (Vec<CountsSnapshot>, Counts)> = HashMap::new();
+    for namespace in std::iter::once(None).chain(config.namespaces.allowed.iter().cloned().map(Some)) {
+        let key = aggregate::aggregate_key(&config.storage.key, namespace.as_deref());
+        let snapshot = aggregate::snapshot_counts(storage, &key).await?;
+        by_namespace.insert(namespace, (snapshot, aggregate::initial_counts()));
+    }
+    let started_at = Utc::now();
+
+    for key in storage.list(&config.events.prefix).await? {
+        if !key.ends_with(".jsonl") {
+            continue;
+        }
+        let object = storage.get(&key).await?;
+        for event in parse_events(&key, &object.data)? {
+            report.events_read += 1;
+            // 不在白名单中的 namespace (例如之后被移除的) 不创建计数对象
+            let Some((_, counts)) = by_namespace.get_mut(&event.namespace) else {
+                tracing::warn!(key = key.as_str(), event_id = event.id.as_str(), namespace = ?event.namespace, "Skipping event for namespace not in namespaces.allowed");
+                report.events_skipped += 1;
+                continue;
+            };
+            aggregate::add_increment(counts, &event.polarity, Some(event.score), event.timestamp);
+        }
+        report.objects_read += 1;
+    }
+
+    for (namespace, (snapshot, mut counts)) in by_namespace {
+        aggregate::prune_hourly(&mut counts, config.aggregate.hourly_retention_days, Utc::now());
+        report.shards_reset += aggregate::replace_counts(storage, &snapshot, &counts).await?;
+        let totals = aggregate::all_time_totals(&counts);
+        match namespace {
+            Some(namespace) => {
+                report.namespaces.insert(namespace, totals);
+            }
+            None => report.totals = totals,
+        }
+    }
+    // 全部计数替换成功后才写入标记
+    let marker = serde_json::to_vec(&RebuildMarker { rebuilt_at: started_at })
+        .map_err(|e| LambdaError::InternalError(format!("Failed to serialize rebuild marker: {}", e)))?;
+    storage.put(&rebuild_marker_key(config), marker, WriteCondition::Always).await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn event(id: &str, polarity: &str, at: DateTime<Utc>) -> SentimentEvent {
+        SentimentEvent {
+            id: id.to_string(),
+            timestamp: at,
+            polarity: polarity.to_string(),
+            score: 0.9,
+            model: "test".to_string(),
+            namespace: None,
+            app_version: "0.1.0".to_string(),
+            tags: vec!["campaign-a".to_string()],
+            text_sha256: Some(sha256_hex("hello")),
+            text: None,
+        }
+    }
+
+    #[test]
+    fn test_event_key_is_date_partitioned() {
+        let at = NaiveDate::from_ymd_opt(2024, 4, 1).unwrap().and_hms_opt(13, 5, 0).unwrap().and_utc();
+        let key = event_key("events/", "abc/def", at);
+        assert!(key.starts_with("events/date=2024-04-01/20240401T130500.000Z-abc_def-"), "{}", key);
+        assert!(key.ends_with(".jsonl"));
+    }
+
+    #[test]
+    fn test_events_round_trip() {
+        let at = Utc::now();
+        let events = vec![event("req-0", "Positive", at), event("req-1", "Negative", at)];
+        let data = serialize_events(&events).unwrap();
+        assert_eq!(data.iter().filter(|&&b| b == b'\n').count(), 2);
+        assert_eq!(parse_events("events/x.jsonl", &data).unwrap(), events);
+        assert_eq!(sha256_hex("hello"), "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_counts_from_events() {
+        let storage = MemoryStorage::new();
+        let mut config = AppConfig::default();
+        config.namespaces.allowed = vec!["shop".to_string(), "support".to_string()];
+        let at = Utc::now();
+        for (index, (polarity, namespace)) in [("Positive", None), ("Positive", None), ("Negative", None), ("Negative", Some("shop")), ("Negative", Some("retired"))].into_iter().enumerate() {
+            let key = event_key(&config.events.prefix, &format!("req-{}", index), at);
+            let mut event = event("req", polarity, at);
+            event.namespace = namespace.map(String::from);
+            storage.put(&key, serialize_events(&[event]).unwrap(), WriteCondition::IfAbsent).await.unwrap();
+        }
+        // 已损坏的计数会被覆盖
+        storage.put("sentiment.csv", b"Sentiment,Count\nPositive,999\n".to_vec(), WriteCondition::Always).await.unwrap();
+
+        let report = rebuild_counts(&storage, &config).await.unwrap();
+        assert_eq!(report.objects_read, 5);
+        assert_eq!(report.events_read, 5);
+        assert_eq!(report.events_skipped, 1);
+        assert!(!report.namespaces.contains_key("retired"));
+        assert!(storage.list("sentiment-namespaces/retired").await.unwrap().is_empty());
+        assert_eq!(report.totals["Positive"], 2);
+        assert_eq!(report.totals["Negative"], 1);
+        assert_eq!(report.namespaces["shop"]["Negative"], 1);
+        // 没有事件的 namespace 重置为 0
+        assert_eq!(report.namespaces["support"]["Positive"], 0);
+
+        let records = aggregate::read_and_parse_csv(&storage, "sentiment.csv").await.unwrap();
+        let counts = aggregate::fold_records(records);
+        assert_eq!(aggregate::all_time_totals(&counts)["Positive"], 2);
+        let today = aggregate::Granularity::Day.bucket(at);
+        assert_eq!(aggregate::range_counts(&counts, aggregate::Granularity::Day, &today, &today)[&today]["Positive"].count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_counter_write_after_snapshot_is_not_counted_twice() {
+        let storage = MemoryStorage::new();
+        let config = AppConfig::default();
+        let at = Utc::now();
+        let mut increments = Counts::new();
+        aggregate::add_increment(&mut increments, "Positive", Some(0.9), at);
+        aggregate::update_sentiment_count(&storage, &config, "sentiment.csv", &increments).await.unwrap();
+
+        // 请求先写事件，重建在它更新计数之前记录快照
+        let key = event_key(&config.events.prefix, "req-1", at);
+        storage.put(&key, serialize_events(&[event("req-1", "Positive", at)]).unwrap(), WriteCondition::IfAbsent).await.unwrap();
+        let snapshot = aggregate::snapshot_counts(&storage, "sentiment.csv").await.unwrap();
+        aggregate::update_sentiment_count(&storage, &config, "sentiment.csv", &increments).await.unwrap();
+
+        // 日志中已有这个事件，计数中也有它的增量：替换被拒绝，而不是计入两次
+        let mut rebuilt = aggregate::initial_counts();
+        aggregate::add_increment(&mut rebuilt, "Positive", Some(0.9), at);
+        let err = aggregate::replace_counts(&storage, &snapshot, &rebuilt).await.unwrap_err();
+        assert!(matches!(err, LambdaError::WriteConflict { .. }), "{:?}", err);
+
+        // 写入停止后重新运行，总数等于日志中的事件数
+        let report = rebuild_counts(&storage, &config).await.unwrap();
+        assert_eq!(report.totals["Positive"], 1);
+        let counts = aggregate::fold_records(aggregate::read_merged_records(&storage, "sentiment.csv").await.unwrap());
+        assert_eq!(aggregate::all_time_totals(&counts)["Positive"], 1);
+    }
+}
@@ -0,0 +1,203 @@
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMicrosecondArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::NaiveDate;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::config::AppConfig;
+use crate::error::LambdaError;
+use crate::events::{self, SentimentEvent};
+use crate::storage::{Storage, WriteCondition};
+
+// 导出文件的列，Athena / DuckDB 按 date=YYYY-MM-DD 分区读取
+pub fn events_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+        Field::new("polarity", DataType::Utf8, false),
+        Field::new("score", DataType::Float64, false),
+        Field::new("model", DataType::Utf8, false),
+        Field::new("namespace", DataType::Utf8, true),
+        Field::new("app_version", DataType::Utf8, false),
+        Field::new("tags", DataType::List(Arc::new(Field::new_list_field(DataType::Utf8, true))), false),
+        Field::new("text_sha256", DataType::Utf8, true),
+    ]))
+}
+
+fn parquet_error(err: impl std::fmt::Display) -> LambdaError {
+    LambdaError::InternalError(format!("Failed to write Parquet: {}", err))
+}
+
+fn events_batch(events: &[SentimentEvent]) -> Result<RecordBatch, LambdaError> {
+    let mut tags = ListBuilder::new(StringBuilder::new());
+    for event in events {
+        for tag in &event.tags {
+            tags.values().append_value(tag);
+        }
+        tags.append(true);
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(events.iter().map(|e| e.id.as_str()))),
+        Arc::new(
+            TimestampMicrosecondArray::from_iter_values(events.iter().map(|e| e.timestamp.timestamp_micros()))
+                .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from_iter_values(events.iter().map(|e| e.polarity.as_str()))),
+        Arc::new(Float64Array::from_iter_values(events.iter().map(|e| e.score))),
+        Arc::new(StringArray::from_iter_values(events.iter().map(|e| e.model.as_str()))),
+        Arc::new(events.iter().map(|e| e.namespace.as_deref()).collect::<StringArray>()),
+        Arc::new(StringArray::from_iter_values(events.iter().map(|e| e.app_version.as_str()))),
+        Arc::new(tags.finish()),
+        Arc::new(events.iter().map(|e| e.text_sha256.as_deref()).collect::<StringArray>()),
+    ];
+    RecordBatch::try_new(events_schema(), columns).map_err(parquet_error)
+}
+
+// 把事件写成一个 Parquet 文件，每 row_group_size 行一个 row group
+pub fn write_parquet(events: &[SentimentEvent], row_group_size: usize) -> Result<Vec<u8>, LambdaError> {
+    let props = WriterProperties::builder()
+        .set_max_row_group_size(row_group_size)
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut data = Vec::new();
+    let mut writer = ArrowWriter::try_new(&mut data, events_schema(), Some(props)).map_err(parquet_error)?;
+    // ArrowWriter 按 max_row_group_size 自动切分 row group
+    writer.write(&events_batch(events)?).map_err(parquet_error)?;
+    writer.close().map_err(parquet_error)?;
+    Ok(data)
+}
+
+pub fn export_key(prefix: &str, date: NaiveDate) -> String {
+    format!("{}events.parquet", events::partition_prefix(prefix, date))
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportReport {
+    pub date: String,
+    pub key: Option<String>,
+    pub rows: usize,
+}
+
+// 把某一天的事件分区导出为 Parquet；重复导出会覆盖同一个文件
+pub async fn export_partition(storage: &dyn Storage, config: &AppConfig, date: NaiveDate) -> Result<ExportReport, LambdaError> {
+    let mut events = events::read_partition(storage, &config.events.prefix, date).await?;
+    let mut report = ExportReport {
+        date: date.format("%Y-%m-%d").to_string(),
+        key: None,
+        rows: events.len(),
+    };
+    if events.is_empty() {
+        return Ok(report);
+    }
+
+    events.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    let key = export_key(&config.export.prefix, date);
+    let data = write_parquet(&events, config.export.row_group_size)?;
+    storage.put(&key, data, WriteCondition::Always).await?;
+    tracing::info!(key, rows = events.len(), "Exported events to Parquet");
+    report.key = Some(key);
+    Ok(report)
+}
+
+// 批处理命令：rust_lambda_hf export-parquet FROM [TO]，日期为 YYYY-MM-DD，闭区间
+pub async fn run_cli(args: &[String], storage: &dyn Storage, config: &AppConfig) -> Result<Vec<ExportReport>, LambdaError> {
+    let parse_date = |value: &str| {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .map_err(|_| LambdaError::InvalidInput(format!("Invalid date {:?}, expected YYYY-MM-DD", value)))
+    };
+    let (from, to) = match args {
+        [from] => (parse_date(from)?, parse_date(from)?),
+        [from, to] => (parse_date(from)?, parse_date(to)?),
+        _ => return Err(LambdaError::InvalidInput("usage: export-parquet FROM [TO]".into())),
+    };
+    if from > to {
+        return Err(LambdaError::InvalidInput("FROM must not be after TO".into()));
+    }
+
+    let mut reports = Vec::new();
+    for date in from.iter_days().take_while(|date| *date <= to) {
+        reports.push(export_partition(storage, config, date).await?);
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, TimestampMicrosecondType};
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn event(index: usize, polarity: &str, tags: &[&str]) -> SentimentEvent {
+        SentimentEvent {
+            id: format!("req-{}", index),
+            timestamp: NaiveDate::from_ymd_opt(2024, 4, 1).unwrap().and_hms_opt(13, 0, index as u32).unwrap().and_utc(),
+            polarity: polarity.to_string(),
+            score: 0.5 + index as f64 / 100.0,
+            model: "test-model".to_string(),
+            namespace: (index == 1).then(|| "shop".to_string()),
+            app_version: "0.1.0".to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            text_sha256: (index % 2 == 0).then(|| format!("hash-{}", index)),
+            text: None,
+        }
+    }
+
+    #[test]
+    fn test_parquet_round_trip() {
+        let events: Vec<SentimentEvent> = (0..5).map(|i| event(i, if i % 2 == 0 { "Positive" } else { "Negative" }, &["a", "b"][..i % 3])).collect();
+        let data = write_parquet(&events, 2).unwrap();
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(data)).unwrap();
+        // 5 行、每组 2 行 -> 3 个 row group
+        assert_eq!(builder.metadata().num_row_groups(), 3);
+        let batches: Vec<RecordBatch> = builder.build().unwrap().collect::<Result<_, _>>().unwrap();
+
+        let mut row = 0;
+        for batch in &batches {
+            let ids = batch.column_by_name("id").unwrap().as_string::<i32>();
+            let timestamps = batch.column_by_name("timestamp").unwrap().as_primitive::<TimestampMicrosecondType>();
+            let scores = batch.column_by_name("score").unwrap().as_primitive::<Float64Type>();
+            let tags = batch.column_by_name("tags").unwrap().as_list::<i32>();
+            let hashes = batch.column_by_name("text_sha256").unwrap().as_string::<i32>();
+            let namespaces = batch.column_by_name("namespace").unwrap().as_string::<i32>();
+            for i in 0..batch.num_rows() {
+                let expected = &events[row];
+                assert_eq!(ids.value(i), expected.id);
+                assert_eq!(timestamps.value(i), expected.timestamp.timestamp_micros());
+                assert_eq!(scores.value(i), expected.score);
+                assert_eq!(tags.value(i).as_string::<i32>().len(), expected.tags.len());
+                assert_eq!(hashes.is_null(i), expected.text_sha256.is_none());
+                assert_eq!(namespaces.is_valid(i).then(|| namespaces.value(i)), expected.namespace.as_deref());
+                row += 1;
+            }
+        }
+        assert_eq!(row, events.len());
+    }
+
+    #[tokio::test]
+    async fn test_export_partition_writes_dated_key() {
+        let storage = MemoryStorage::new();
+        let config = AppConfig::default();
+        let date = NaiveDate::from_ymd_opt(2024, 4, 1).unwrap();
+        let data = events::serialize_events(&[event(1, "Positive", &[]), event(0, "Negative", &["x"])]).unwrap();
+        storage.put("events/date=2024-04-01/a.jsonl", data, WriteCondition::IfAbsent).await.unwrap();
+
+        let report = export_partition(&storage, &config, date).await.unwrap();
+        assert_eq!(report.rows, 2);
+        assert_eq!(report.key.as_deref(), Some("exports/date=2024-04-01/events.parquet"));
+        assert!(storage.head("exports/date=2024-04-01/events.parquet").await.is_ok());
+
+        // 没有事件的日期不写文件
+        let empty = export_partition(&storage, &config, date.succ_opt().unwrap()).await.unwrap();
+        assert_eq!(empty.rows, 0);
+        assert!(empty.key.is_none());
+    }
+}
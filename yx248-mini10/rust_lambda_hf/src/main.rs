@@ -1,198 +1,758 @@
 use tracing::{Level};
 use tracing_subscriber::FmtSubscriber;
 use tracing_subscriber::filter::EnvFilter;
-use lambda_http::{run, service_fn, Body, Error, Request, Response, http, http::StatusCode};
+use lambda_http::{run, service_fn, Body, Error, Request, RequestExt, Response, http, http::StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_urlencoded;
-use aws_sdk_s3::Client as S3Client;
-use aws_sdk_s3::primitives::ByteStream;
-use aws_config::{self, load_defaults, BehaviorVersion};
-use csv::{ReaderBuilder, WriterBuilder};
-use rust_bert::pipelines::sentiment::{SentimentModel, Sentiment};
+use rust_bert::pipelines::sentiment::{Sentiment, SentimentPolarity};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use thiserror::Error;
-use std::collections::HashMap;
-
-#[derive(Error, Debug)]
-pub enum LambdaError {
-    #[error("Invalid command")]
-    InvalidCommand,
-    #[error("Sentiment analysis error")]
-    SentimentError,
-    #[error("AWS S3 Error")]
-    S3Error,
-    #[error("Internal Error: {0}")]
-    InternalError(String),
-}
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap};
+
+mod aggregate;
+mod buffer;
+mod classifier;
+mod config;
+mod error;
+mod events;
+mod export;
+mod outbox;
+mod retry;
+mod router;
+mod storage;
+
+use aggregate::{Aggregate, Granularity};
+use config::AppConfig;
+use error::{error_body, LambdaError};
+use buffer::WriteBuffer;
+use classifier::SentimentClassifier;
+use outbox::Outbox;
+use router::{Route, RouteMatch};
+use storage::Storage;
 
 #[derive(Deserialize, Serialize)]
 pub struct LambdaInput {
+    #[serde(default = "default_command")]
     pub command: String,
+    #[serde(default)]
     pub text: String,
+    // 批量模式 (command = "sentiment_batch") 使用的文本列表
+    #[serde(default)]
+    pub texts: Vec<String>,
+    // 兼容旧客户端：同时输出旧格式的 result 字符串；不传时使用 features.legacy_result
+    #[serde(default)]
+    pub legacy: Option<bool>,
+    // 写入事件日志和导出的标签，不影响分析结果
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // 分开计数的 namespace（产品 / 渠道 / 租户），必须在 namespaces.allowed 中
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+// 响应结构的版本号，字段有不兼容变更时递增
+pub const RESPONSE_SCHEMA_VERSION: u32 = 1;
+// Default::default() 加载的模型
+pub const MODEL_ID: &str = "distilbert-base-uncased-finetuned-sst-2-english";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Polarity {
+    Positive,
+    Negative,
+}
+
+impl Polarity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Polarity::Positive => "Positive",
+            Polarity::Negative => "Negative",
+        }
+    }
+}
+
+impl From<&SentimentPolarity> for Polarity {
+    fn from(polarity: &SentimentPolarity) -> Self {
+        match polarity {
+            SentimentPolarity::Positive => Polarity::Positive,
+            SentimentPolarity::Negative => Polarity::Negative,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SentimentResult {
+    pub polarity: Polarity,
+    pub score: f64,
+    pub low_confidence: bool,
+    pub input_length: usize,
+}
+
+impl SentimentResult {
+    fn new(sentiment: &Sentiment, text: &str, config: &AppConfig) -> Self {
+        SentimentResult {
+            polarity: Polarity::from(&sentiment.polarity),
+            score: sentiment.score,
+            low_confidence: sentiment.score < config.thresholds.low_confidence,
+            input_length: text.chars().count(),
+        }
+    }
+}
+
+// 本次请求的计数增量的去向
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CountStatus {
+    Written,
+    // 还在写缓冲中，由之后的调用 (达到阈值或超时) 或关闭时写入
+    Buffered,
+    // 写入失败，已放入 outbox 等待重试；outbox 不可用时丢失，见 error 日志
+    Failed,
 }
 
 #[derive(Serialize)]
 pub struct LambdaOutput {
-    pub result: String,
+    pub schema_version: u32,
+    pub request_id: String,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sentiment: Option<SentimentResult>,
+    // 批量模式下每条文本的结果，顺序与输入一致
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub results: Vec<SentimentResult>,
+    // 旧格式 "Sentiment: Sentiment { .. }"，默认按 features.legacy_result 输出，请求中的 legacy 可以覆盖
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+    // 本次请求的计数增量已写入存储时为 true，即 counts 为 written。
+    // 只反映计数：事件日志写入失败只记录 warn 日志，不影响这个字段
+    pub persisted: bool,
+    pub counts: CountStatus,
+}
+
+impl LambdaOutput {
+    fn new(request_id: String, config: &AppConfig) -> Self {
+        LambdaOutput {
+            schema_version: RESPONSE_SCHEMA_VERSION,
+            request_id,
+            model: config.model.id().to_string(),
+            sentiment: None,
+            results: Vec::new(),
+            result: None,
+            persisted: true,
+            counts: CountStatus::Written,
+        }
+    }
+
+    fn set_count_status(&mut self, status: CountStatus) {
+        self.persisted = status == CountStatus::Written;
+        self.counts = status;
+    }
+}
+
+// 检查单条文本：不能为空或只有空白，也不能超过配置的长度限制
+fn check_text(text: &str, field: &str, config: &AppConfig) -> Result<(), LambdaError> {
+    if text.trim().is_empty() {
+        return Err(LambdaError::InvalidInput(format!("{} must not be empty", field)));
+    }
+    if text.chars().count() > config.limits.max_text_length {
+        return Err(LambdaError::InvalidInput(format!(
+            "text exceeds {} characters",
+            config.limits.max_text_length
+        )));
+    }
+    Ok(())
+}
+
+// 不在白名单中的 namespace 直接拒绝，避免任意创建计数对象
+fn check_namespace(namespace: Option<&str>, config: &AppConfig) -> Result<(), LambdaError> {
+    match namespace {
+        Some(namespace) if !config.namespaces.is_allowed(namespace) => {
+            Err(LambdaError::InvalidInput(format!("Unknown namespace {:?}", namespace)))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn process_input(input: LambdaInput, request_id: String, state: &AppState) -> Result<LambdaOutput, LambdaError> {
+    let config = &state.config;
+    check_namespace(input.namespace.as_deref(), config)?;
+    let mut output = LambdaOutput::new(request_id.clone(), config);
+    let legacy = input.legacy.unwrap_or(config.features.legacy_result);
+    let context = events::EventContext {
+        request_id: &request_id,
+        namespace: input.namespace.as_deref(),
+        tags: &input.tags,
+    };
+    match input.command.as_str() {
+        "sentiment" => {
+            check_text(&input.text, "text", config)?;
+            // 使用传入的sentiment_model进行情绪分析
+            let sentiment = analyze_sentiment(&input.text, state.classifier.as_ref()).await?;
+            output.set_count_status(persist_results(state, &context, std::slice::from_ref(&sentiment), &[input.text.as_str()]).await);
+            if legacy {
+                output.result = Some(format!("Sentiment: {:?}", sentiment));
+            }
+            output.sentiment = Some(SentimentResult::new(&sentiment, &input.text, config));
+            Ok(output)
+        }
+        "sentiment_batch" => {
+            if !config.features.batch {
+                return Err(LambdaError::FeatureDisabled("sentiment_batch".into()));
+            }
+            if input.texts.is_empty() {
+                return Err(LambdaError::InvalidInput("texts must not be empty".into()));
+            }
+            if input.texts.len() > config.limits.max_batch_size {
+                return Err(LambdaError::InvalidInput(format!(
+                    "texts exceeds batch size limit of {}",
+                    config.limits.max_batch_size
+                )));
+            }
+            for (index, text) in input.texts.iter().enumerate() {
+                check_text(text, &format!("texts[{}]", index), config)?;
+            }
+            let inputs: Vec<&str> = input.texts.iter().map(String::as_str).collect();
+            let sentiments = analyze_batch(&inputs, state.classifier.as_ref()).await?;
+            output.set_count_status(persist_results(state, &context, &sentiments, &inputs).await);
+            if legacy {
+                output.result = Some(format!("Sentiments: {:?}", sentiments));
+            }
+            output.results = sentiments
+                .iter()
+                .zip(&input.texts)
+                .map(|(sentiment, text)| SentimentResult::new(sentiment, text, config))
+                .collect();
+            Ok(output)
+        }
+        _ => Err(LambdaError::InvalidCommand),
+    }
+}
+
+async fn analyze_sentiment(text: &str, classifier: &dyn SentimentClassifier) -> Result<Sentiment, LambdaError> {
+    let sentiments = classifier.predict(&[text]).await?;
+    sentiments.into_iter().next().ok_or(LambdaError::SentimentError)
+}
+
+// 批量分析：一次模型调用处理全部文本
+async fn analyze_batch(texts: &[&str], classifier: &dyn SentimentClassifier) -> Result<Vec<Sentiment>, LambdaError> {
+    let sentiments = classifier.predict(texts).await?;
+
+    // 模型应为每条输入返回一个结果
+    if sentiments.len() != texts.len() {
+        return Err(LambdaError::SentimentError);
+    }
+    Ok(sentiments)
+}
+
+// 写入事件并更新计数；存储失败不影响返回分析结果，返回值表示计数增量的去向
+async fn persist_results(state: &AppState, context: &events::EventContext<'_>, sentiments: &[Sentiment], texts: &[&str]) -> CountStatus {
+    let config = &state.config;
+    let storage = state.storage.as_ref();
+
+    // 先写事件再更新计数：计数失败时仍可以从事件日志重建。
+    // 事件不进入 outbox，写入失败时这些事件会在重建时缺失
+    let now = chrono::Utc::now();
+    let logged_at = match events::append_events(storage, config, context, sentiments, texts, now).await {
+        Ok(()) => config.events.enabled.then_some(now),
+        Err(e) => {
+            tracing::warn!(request_id = context.request_id, error = %e, "Failed to append sentiment events");
+            None
+        }
+    };
+
+    // 增量先进入写缓冲，达到阈值时和之前请求的增量一起写入
+    let Some(pending) = state.buffer.add(buffer::PendingIncrement {
+        key: aggregate::aggregate_key(&config.storage.key, context.namespace),
+        increments: aggregate::sentiment_increments(sentiments, now),
+        logged_at,
+    }) else {
+        return CountStatus::Buffered;
+    };
+    if flush_counts(state, pending).await {
+        CountStatus::Written
+    } else {
+        CountStatus::Failed
+    }
+}
+
+// 空闲的实例可能很久才收到下一次请求：读写计数的调用开始时先写入超过 max_age_ms 的缓冲增量
+async fn flush_expired(state: &AppState) {
+    if let Some(pending) = state.buffer.take_expired() {
+        tracing::info!(requests = pending.len(), "Flushing expired buffered counts");
+        flush_counts(state, pending).await;
+    }
 }
 
-async fn process_input(input: LambdaInput, sentiment_model: Arc<Mutex<SentimentModel>>) -> Result<LambdaOutput, LambdaError> {
-    if input.command == "sentiment" {
-        // 使用传入的sentiment_model进行情绪分析
-        let sentiment = analyze_sentiment_and_update_s3(&input.text, "sentiments-data", sentiment_model).await?;
-        Ok(LambdaOutput {
-            result: format!("Sentiment: {:?}", sentiment),
+// 写入失败的增量放入 outbox；outbox 也不可用时只能丢弃并记录
+async fn spool(state: &AppState, key: &str, increments: &aggregate::Counts, logged_at: Option<chrono::DateTime<chrono::Utc>>) {
+    if let Err(e) = state.outbox.push(key, increments, logged_at).await {
+        tracing::error!(key, error = %e, "Dropped sentiment count increment");
+    }
+}
+
+// 每个计数对象做一次读-改-写；写入失败的增量放入 outbox，不会丢失
+async fn flush_counts(state: &AppState, pending: Vec<buffer::PendingIncrement>) -> bool {
+    let config = &state.config;
+    let storage = state.storage.as_ref();
+
+    // 缓冲中的增量可能早于最近一次重建：事件已在日志中的部分已被重建计入，跳过，否则会计入两次
+    let pending = if pending.iter().any(|increment| increment.logged_at.is_some()) {
+        match events::rebuilt_at(storage, config).await {
+            Ok(rebuilt_at) => {
+                let total = pending.len();
+                let pending: Vec<_> = pending
+                    .into_iter()
+                    .filter(|increment| !events::counted_by_rebuild(increment.logged_at, rebuilt_at))
+                    .collect();
+                if pending.len() < total {
+                    tracing::info!(skipped = total - pending.len(), "Skipped buffered counts already counted by rebuild");
+                }
+                pending
+            }
+            Err(e) => {
+                // 不知道是否已被重建计入：逐条放入 outbox，保留各自的 logged_at，重放时再判断
+                tracing::warn!(error = %e, "Failed to read rebuild marker, spooling buffered counts to outbox");
+                for increment in &pending {
+                    spool(state, &increment.key, &increment.increments, increment.logged_at).await;
+                }
+                return false;
+            }
+        }
+    } else {
+        pending
+    };
+
+    let mut flushed = true;
+    for group in buffer::merge(pending) {
+        if let Err(e) = aggregate::update_sentiment_count(storage, config, &group.key, &group.increments).await {
+            tracing::warn!(key = group.key.as_str(), error = %e, "Failed to update sentiment count, spooling to outbox");
+            spool(state, &group.key, &group.increments, group.logged_at).await;
+            flushed = false;
+        }
+    }
+    // 存储恢复后顺便重放之前失败的增量
+    if flushed {
+        if let Err(e) = state.outbox.drain(storage, config).await {
+            tracing::warn!(error = %e, "Failed to replay outbox");
+        }
+    }
+    flushed
+}
+
+fn error_response(status: StatusCode, code: &str, message: &str, request_id: &str) -> Response<Body> {
+    json_response(status, error_body(code, message, request_id))
+}
+
+// 将 LambdaError 转换为对应状态码的 JSON 错误响应；完整的错误只写入日志，客户端错误不按 error 级别记录
+fn lambda_error_response(err: &LambdaError, request_id: &str) -> Response<Body> {
+    let status = err.status_code();
+    if status.is_server_error() {
+        tracing::error!(request_id, code = err.code(), "{}", err);
+    } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        tracing::warn!(request_id, code = err.code(), "{}", err);
+    } else {
+        tracing::info!(request_id, code = err.code(), "{}", err);
+    }
+    error_response(status, err.code(), &err.public_message(), request_id)
+}
+
+// Lambda 的 request id；本地运行没有 context 时使用 "local"
+fn request_id(event: &Request) -> String {
+    event
+        .lambda_context_ref()
+        .map(|ctx| ctx.request_id.clone())
+        .unwrap_or_else(|| "local".to_string())
+}
+
+fn default_command() -> String {
+    "sentiment".to_string()
+}
+
+// 从请求中解析 LambdaInput：GET 读取查询参数，POST 读取 JSON 或表单 body
+fn parse_input(event: &Request) -> Result<LambdaInput, LambdaError> {
+    if event.method() == http::Method::GET {
+        // 解析 URL 查询参数
+        let query_params = event.uri().query().unwrap_or("");
+        let query_map: HashMap<String, String> = serde_urlencoded::from_str(query_params)
+            .map_err(|_| LambdaError::InvalidInput("Invalid query parameters".into()))?;
+
+        // 从查询参数中提取 'text' 字段，command 可选
+        let text = query_map.get("text").ok_or_else(|| LambdaError::InvalidInput("Missing text parameter".into()))?;
+        Ok(LambdaInput {
+            command: query_map.get("command").cloned().unwrap_or_else(default_command),
+            text: text.clone(),
+            texts: Vec::new(),
+            legacy: query_map.get("legacy").map(|value| value == "true" || value == "1"),
+            tags: query_map
+                .get("tags")
+                .map(|value| value.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(String::from).collect())
+                .unwrap_or_default(),
+            namespace: query_map.get("namespace").cloned(),
         })
     } else {
-        Err(LambdaError::InvalidCommand)
+        let content_type = event
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/json");
+        let body: &[u8] = event.body();
+
+        if body.is_empty() {
+            return Err(LambdaError::InvalidInput("Missing request body".into()));
+        }
+
+        if content_type.starts_with("application/x-www-form-urlencoded") {
+            serde_urlencoded::from_bytes::<LambdaInput>(body)
+                .map_err(|e| LambdaError::InvalidInput(format!("Invalid form body: {}", e)))
+        } else {
+            serde_json::from_slice::<LambdaInput>(body)
+                .map_err(|e| LambdaError::InvalidInput(format!("Invalid JSON body: {}", e)))
+        }
     }
 }
 
-async fn analyze_sentiment_and_update_s3(text: &str, bucket: &str, sentiment_model: Arc<Mutex<SentimentModel>>) -> Result<Sentiment, LambdaError> {
-    // 获取Mutex的锁
-    let model = sentiment_model.lock().await;
-    let sentiments = model.predict(&[text]);
-    let sentiment = sentiments.into_iter().next().ok_or(LambdaError::SentimentError)?;
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(body.to_string().into())
+        .expect("Failed to render response")
+}
+
+// main 中构建一次、在所有调用之间共享的状态：模型、配置和存储客户端 (S3Client)
+pub struct AppState {
+    pub classifier: Arc<dyn SentimentClassifier>,
+    pub model_status: ModelStatus,
+    pub config: AppConfig,
+    pub storage: Arc<dyn Storage>,
+    // 尚未写入存储的计数增量
+    pub buffer: WriteBuffer,
+    // 计数更新失败时保存增量，之后的调用中重试
+    pub outbox: Outbox,
+}
+
+async fn function_handler(event: Request, state: Arc<AppState>) -> Result<Response<Body>, Error> {
+    let request_id = request_id(&event);
+    let config = &state.config;
+    let storage = state.storage.as_ref();
+    let route = router::resolve(event.method(), event.uri().path(), &config.server.base_path);
 
-    let config = load_defaults(BehaviorVersion::latest()).await;
-    let s3_client = S3Client::new(&config);
-    update_sentiment_count_in_s3(&s3_client, bucket, &sentiment).await?;
+    // 只在读写计数的路由上写入过期的缓冲增量；健康检查不访问存储，也不为此变慢
+    if matches!(route, RouteMatch::Found(Route::Sentiment | Route::Stats | Route::Compact | Route::Export)) {
+        flush_expired(&state).await;
+    }
 
-    Ok(sentiment)
+    // 按 method 和 path 分发到各个 handler
+    let response = match route {
+        RouteMatch::Found(Route::Sentiment) => sentiment_handler(&event, &request_id, &state).await,
+        RouteMatch::Found(Route::Stats) => stats_handler(&event, &request_id, config, storage).await,
+        RouteMatch::Found(Route::Health) => health_handler(&state.model_status),
+        RouteMatch::Found(Route::Ready) => readiness_handler(&state.model_status, config, storage).await,
+        RouteMatch::Found(Route::Version) => version_handler(config),
+        RouteMatch::Found(Route::Compact) => compact_handler(&event, &request_id, config, storage).await,
+        RouteMatch::Found(Route::Export) => export_handler(&event, &request_id, config, storage).await,
+        RouteMatch::MethodNotAllowed(route) => {
+            tracing::warn!(request_id, method = %event.method(), route = ?route, "Method not allowed");
+            let mut response = error_response(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "Method not allowed", &request_id);
+            response.headers_mut().insert(
+                http::header::ALLOW,
+                http::HeaderValue::from_static(route.allow_header()),
+            );
+            response
+        }
+        RouteMatch::NotFound => {
+            tracing::warn!(request_id, method = %event.method(), "Route not found");
+            error_response(StatusCode::NOT_FOUND, "not_found", "Not found", &request_id)
+        }
+    };
+
+    Ok(response)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SentimentRecord {
-    #[serde(rename = "Sentiment")]
-    sentiment: String,
-    #[serde(rename = "Count")]
+#[derive(Serialize, Debug)]
+struct PolarityStats {
     count: i32,
+    percentage: f64,
+    // 分数统计只来自带分数的记录，旧数据只有计数
+    scored: i32,
+    mean_score: Option<f64>,
+    std_dev: Option<f64>,
+    min_score: Option<f64>,
+    max_score: Option<f64>,
+    histogram: [i32; aggregate::HISTOGRAM_BINS],
 }
 
-async fn read_and_parse_csv(client: &S3Client, bucket: &str, key: &str) -> Result<Vec<SentimentRecord>, LambdaError> {
-    let get_req = client.get_object()
-                        .bucket(bucket)
-                        .key(key)
-                        .send()
-                        .await
-                        .map_err(|_| LambdaError::S3Error)?;
+#[derive(Serialize, Debug)]
+struct SentimentStats {
+    total: i64,
+    polarities: BTreeMap<String, PolarityStats>,
+}
 
-    let bytes_stream = get_req
-        .body
-        .collect()
-        .await
-        .map_err(|_| LambdaError::S3Error)?;
+#[derive(Serialize, Debug)]
+struct BucketStats {
+    bucket: String,
+    #[serde(flatten)]
+    stats: SentimentStats,
+}
 
-    let bytes = bytes_stream.into_bytes();
+// 根据某个时间桶（或总计）的统计计算每种情感的数量、占比和分数分布
+fn compute_stats(counts: BTreeMap<String, Aggregate>) -> SentimentStats {
+    let total: i64 = counts.values().map(|aggregate| i64::from(aggregate.count)).sum();
 
-    let csv_content = std::str::from_utf8(&bytes)
-        .map_err(|_| LambdaError::InternalError("Invalid UTF-8 sequence".into()))?;
+    let polarities = counts
+        .into_iter()
+        .map(|(sentiment, aggregate)| {
+            let percentage = if total > 0 {
+                f64::from(aggregate.count) * 100.0 / total as f64
+            } else {
+                0.0
+            };
+            let stats = PolarityStats {
+                count: aggregate.count,
+                percentage,
+                scored: aggregate.scored,
+                mean_score: aggregate.mean(),
+                std_dev: aggregate.std_dev(),
+                min_score: aggregate.score_min,
+                max_score: aggregate.score_max,
+                histogram: aggregate.histogram,
+            };
+            (sentiment, stats)
+        })
+        .collect();
 
-    let mut rdr = ReaderBuilder::new().from_reader(csv_content.as_bytes());
-    let records: Result<Vec<SentimentRecord>, csv::Error> = rdr.deserialize().collect();
-    records.map_err(|_| LambdaError::InternalError("Failed to parse CSV".into()))
+    SentimentStats { total, polarities }
 }
 
-async fn update_sentiment_count_in_s3(client: &S3Client, bucket: &str, update_sentiment: &Sentiment) -> Result<(), LambdaError> {
-    let key = "sentiment.csv";
-    let records = read_and_parse_csv(client, bucket, key).await?;
-    // println!("Read records: {:?}", records);  // check if records are read correctly
+// /stats?granularity=day|hour&from=..&to=.. 的范围查询参数，from/to 为闭区间 (UTC)
+struct RangeQuery {
+    granularity: Granularity,
+    from: String,
+    to: String,
+}
 
-    // 更新情感计数
-    let mut map = records.into_iter().fold(HashMap::new(), |mut acc, rec| {
-        // acc.entry(rec.sentiment).or_insert(rec.count);
-        *acc.entry(rec.sentiment).or_insert(0) += rec.count; // 注意这里需要进行累加
-        acc
-    });
-    // 定义更新键的变量
-    let key_to_update = format!("{:?}", update_sentiment.polarity);
-    // println!("Trying to access key: {:?}", key_to_update);
-    // println!("Trying to access key: {:?}", format!("{:?}", update_sentiment.polarity)); // check map key
+fn parse_range_query(query_map: &HashMap<String, String>) -> Result<Option<RangeQuery>, LambdaError> {
+    let granularity = match query_map.get("granularity") {
+        Some(value) => Granularity::parse(value)
+            .ok_or_else(|| LambdaError::InvalidInput(format!("Unknown granularity {:?}, expected day or hour", value)))?,
+        None if query_map.contains_key("from") || query_map.contains_key("to") => Granularity::Day,
+        None => return Ok(None),
+    };
 
-    // *map.get_mut(&format!("{:?}", update_sentiment.polarity)).unwrap() += 1;
-    *map.entry(key_to_update).or_insert(0) += 1; // 直接使用 entry API 安全更新或插入
+    let bound = |name: &str, end: bool| -> Result<String, LambdaError> {
+        let value = query_map
+            .get(name)
+            .ok_or_else(|| LambdaError::InvalidInput(format!("Missing {} parameter", name)))?;
+        granularity.normalize(value, end).ok_or_else(|| {
+            LambdaError::InvalidInput(format!("Invalid {} {:?}, expected YYYY-MM-DD or YYYY-MM-DDTHH", name, value))
+        })
+    };
+    let from = bound("from", false)?;
+    let to = bound("to", true)?;
+    if from > to {
+        return Err(LambdaError::InvalidInput("from must not be after to".into()));
+    }
+    Ok(Some(RangeQuery { granularity, from, to }))
+}
 
-    // println!("Updated map: {:?}", map);  // check if map is updated correctly
+async fn stats_handler(event: &Request, request_id: &str, config: &AppConfig, storage: &dyn Storage) -> Response<Body> {
+    if !config.features.stats {
+        return lambda_error_response(&LambdaError::FeatureDisabled("stats".into()), request_id);
+    }
+    // /stats?namespace=.. 只统计该 namespace 的计数
+    let query = serde_urlencoded::from_str::<HashMap<String, String>>(event.uri().query().unwrap_or(""))
+        .map_err(|_| LambdaError::InvalidInput("Invalid query parameters".into()))
+        .and_then(|query_map| {
+            let namespace = query_map.get("namespace").cloned();
+            check_namespace(namespace.as_deref(), config)?;
+            Ok((namespace, parse_range_query(&query_map)?))
+        });
+    let (namespace, range) = match query {
+        Ok(query) => query,
+        Err(e) => return lambda_error_response(&e, request_id),
+    };
+    let key = aggregate::aggregate_key(&config.storage.key, namespace.as_deref());
+    let counts = match aggregate::read_merged_records(storage, &key).await {
+        Ok(records) => aggregate::fold_records(records),
+        Err(e) => return lambda_error_response(&e, request_id),
+    };
 
-    // 将更新后的数据写回 CSV
-    let mut wtr = Vec::new();
+    match range {
+        None => json_response(StatusCode::OK, json!(compute_stats(aggregate::all_time(&counts)))),
+        Some(range) => {
+            let series = aggregate::range_counts(&counts, range.granularity, &range.from, &range.to);
+            let mut totals: BTreeMap<String, Aggregate> = BTreeMap::new();
+            for bucket_counts in series.values() {
+                for (sentiment, aggregate) in bucket_counts {
+                    totals.entry(sentiment.clone()).or_default().merge(aggregate);
+                }
+            }
+            let buckets: Vec<BucketStats> = series
+                .into_iter()
+                .map(|(bucket, bucket_counts)| BucketStats { bucket, stats: compute_stats(bucket_counts) })
+                .collect();
+            json_response(StatusCode::OK, json!({
+                "namespace": namespace,
+                "granularity": range.granularity.as_str(),
+                "from": range.from,
+                "to": range.to,
+                "total": compute_stats(totals),
+                "buckets": buckets,
+            }))
+        }
+    }
+}
 
-    {
-        let mut csv_writer = WriterBuilder::new().from_writer(&mut wtr);
-        for (sentiment, count) in &map {
-            csv_writer.serialize(SentimentRecord { sentiment: sentiment.clone(), count: *count })
-                .map_err(|_| LambdaError::InternalError("Failed to write CSV".into()))?;
+// /admin/* 需要开启 features.admin 并带上正确的 x-admin-token
+fn check_admin(event: &Request, config: &AppConfig) -> Result<(), LambdaError> {
+    if !config.features.admin {
+        return Err(LambdaError::FeatureDisabled("admin".into()));
+    }
+    let expected = config.server.admin_token.as_deref().unwrap_or("");
+    let provided = event.headers().get("x-admin-token").map(|value| value.as_bytes()).unwrap_or(b"");
+    if expected.is_empty() || !constant_time_eq(provided, expected.as_bytes()) {
+        tracing::warn!(path = event.uri().path(), "Rejected admin request");
+        return Err(LambdaError::Unauthorized);
+    }
+    Ok(())
+}
+
+// 比较时间不依赖于第一个不同字节的位置
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// 把分片计数合并回 sentiment.csv
+async fn compact_handler(event: &Request, request_id: &str, config: &AppConfig, storage: &dyn Storage) -> Response<Body> {
+    if let Err(e) = check_admin(event, config) {
+        return lambda_error_response(&e, request_id);
+    }
+    match aggregate::compact_shards(storage, config).await {
+        Ok(report) => {
+            tracing::info!(?report, "Compacted counter shards");
+            json_response(StatusCode::OK, json!(report))
         }
+        Err(e) => lambda_error_response(&e, request_id),
+    }
+}
 
-        // Crucial step: Flush the writer
-        csv_writer.flush().map_err(|_| LambdaError::InternalError("Failed to flush CSV".into()))?;
+// 把某一天的事件导出为 Parquet：/admin/export?date=YYYY-MM-DD，默认当天 (UTC)
+async fn export_handler(event: &Request, request_id: &str, config: &AppConfig, storage: &dyn Storage) -> Response<Body> {
+    if let Err(e) = check_admin(event, config) {
+        return lambda_error_response(&e, request_id);
+    }
+    if !config.events.enabled {
+        return lambda_error_response(&LambdaError::FeatureDisabled("events".into()), request_id);
+    }
+    let query_map: HashMap<String, String> = match serde_urlencoded::from_str(event.uri().query().unwrap_or("")) {
+        Ok(query_map) => query_map,
+        Err(_) => return lambda_error_response(&LambdaError::InvalidInput("Invalid query parameters".into()), request_id),
+    };
+    let date = match query_map.get("date") {
+        Some(value) => match chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) => {
+                let err = LambdaError::InvalidInput(format!("Invalid date {:?}, expected YYYY-MM-DD", value));
+                return lambda_error_response(&err, request_id);
+            }
+        },
+        None => chrono::Utc::now().date_naive(),
+    };
+    match export::export_partition(storage, config, date).await {
+        Ok(report) => json_response(StatusCode::OK, json!(report)),
+        Err(e) => lambda_error_response(&e, request_id),
     }
+}
 
-    // Now you can safely move the contents of 'wtr'
-    let data = wtr;
+// main 中加载模型时记录的状态，供 health 检查使用
+pub struct ModelStatus {
+    pub model: String,
+    pub backend: &'static str,
+    pub loaded: bool,
+    pub load_time_ms: u128,
+    pub loaded_at: u64,
+}
 
-    // 写回到 S3
-    client.put_object()
-        .bucket(bucket)
-        .key(key)
-        .body(ByteStream::from(data))
-        .send()
-        .await
-        .map_err(|_| LambdaError::S3Error)?;
+fn build_info() -> serde_json::Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": option_env!("GIT_SHA").unwrap_or("unknown"),
+    })
+}
 
-    Ok(())
+fn model_info(model_status: &ModelStatus) -> serde_json::Value {
+    json!({
+        "id": model_status.model,
+        "backend": model_status.backend,
+        "loaded": model_status.loaded,
+        "load_time_ms": model_status.load_time_ms,
+        "loaded_at": model_status.loaded_at,
+    })
 }
 
-async fn function_handler(event: Request, sentiment_model: Arc<Mutex<SentimentModel>>) -> Result<Response<Body>, Error> {
-    // 确保请求是 GET 请求
-    if event.method() == http::Method::GET {
-        // 解析 URL 查询参数
-        let query_params = event.uri().query().unwrap_or("");
-        let query_map: Result<HashMap<String, String>, _> = serde_urlencoded::from_str(query_params);
+// liveness：进程在运行且模型已加载，不访问 S3
+fn health_handler(model_status: &ModelStatus) -> Response<Body> {
+    let status = if model_status.loaded { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    json_response(status, json!({
+        "status": if model_status.loaded { "ok" } else { "unavailable" },
+        "model": model_info(model_status),
+        "build": build_info(),
+    }))
+}
 
-        if let Err(_) = query_map {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .header("Content-Type", "application/json")
-                .body(json!({"error": "Invalid query parameters"}).to_string().into())
-                .expect("Failed to render response"));
+// readiness：模型已加载且存储可访问时才能接收流量
+async fn readiness_handler(model_status: &ModelStatus, config: &AppConfig, storage: &dyn Storage) -> Response<Body> {
+    // 对 sentiment.csv 做 HEAD 请求，检查存储是否可访问；对象不存在时第一次写入会自动创建。
+    // HEAD 的 404 也可能是桶不存在，要先确认桶存在才算"尚未初始化"
+    let storage_check = match storage.head(&config.storage.key).await {
+        Ok(()) => Ok(true),
+        Err(e) if matches!(e.storage_kind(), Some(error::StorageErrorKind::NoSuchKey | error::StorageErrorKind::NotFound)) => {
+            storage.head_bucket().await.map(|()| false)
         }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &storage_check {
+        tracing::warn!(error = %e, "Readiness storage check failed");
+    }
+    let ready = model_status.loaded && storage_check.is_ok();
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
 
-        // 从查询参数中提取 'text' 字段
-        if let Some(text) = query_map.unwrap().get("text") {
-            let input = LambdaInput {
-                command: "sentiment".to_string(),
-                text: text.clone(),
-            };
-            let output = process_input(input, sentiment_model).await.map_err(|e| {
-                LambdaError::InternalError(format!("Failed to process input: {}", e))
-            })?;
-
-            println!("{:?}", json!(output).to_string());
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "application/json")
-                .body(json!(output).to_string().into())
-                .expect("Failed to render response"))
-        } else {
-            // 如果没有提供 text 参数，返回错误响应
-            println!("error: Missing text parameter. Failed to render response.");
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .header("Content-Type", "application/json")
-                .body(json!({"error": "Missing text parameter"}).to_string().into())
-                .expect("Failed to render response"));
+    json_response(status, json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "model": model_info(model_status),
+        "storage": {
+            "backend": storage.backend(),
+            "location": storage.location(&config.storage.key),
+            "reachable": storage_check.is_ok(),
+            "initialized": storage_check.as_ref().ok().copied(),
+            "error": storage_check.err().map(|e| e.public_message()),
+        },
+        "build": build_info(),
+    }))
+}
+
+fn version_handler(config: &AppConfig) -> Response<Body> {
+    json_response(StatusCode::OK, json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "model": config.model.id(),
+        "schema_version": RESPONSE_SCHEMA_VERSION,
+    }))
+}
+
+async fn sentiment_handler(event: &Request, request_id: &str, state: &AppState) -> Response<Body> {
+    let input = match parse_input(event) {
+        Ok(input) => input,
+        Err(e) => return lambda_error_response(&e, request_id),
+    };
+
+    match process_input(input, request_id.to_string(), state).await {
+        Ok(output) => {
+            // 响应包含原文，只在 debug 级别记录
+            tracing::debug!(request_id, response = %json!(output), "Sentiment response");
+            json_response(StatusCode::OK, json!(output))
         }
-    } else {
-        // 如果不是 GET 请求，返回方法不允许
-        println!("error: Method not allowed. Failed to render response.");
-        Ok(Response::builder()
-            .status(StatusCode::METHOD_NOT_ALLOWED)
-            .header("Content-Type", "application/json")
-            .body(json!({"error": "Method not allowed"}).to_string().into())
-            .expect("Failed to render response"))
+        Err(e) => lambda_error_response(&e, request_id),
     }
 }
 
@@ -209,11 +769,459 @@ async fn main() -> Result<(), lambda_http::Error> {
     tracing::subscriber::set_global_default(subscriber)
         .expect("Failed to set subscriber");
 
+    // 启动时加载并校验配置，配置错误直接退出
+    let config = match AppConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {}", e);
+            return Err(e.into());
+        }
+    };
+    let storage = storage::from_config(&config.storage).await;
+    tracing::info!("Using {} storage at {}", storage.backend(), storage.location(&config.storage.key));
+
+    // 批处理命令不需要加载模型，也不启动 Lambda runtime
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("export-parquet") => {
+            for report in export::run_cli(&args[1..], storage.as_ref(), &config).await? {
+                println!("{}", json!(report));
+            }
+            return Ok(());
+        }
+        // 从事件日志重建计数：每个事件对象读一次，不适合放在 API Gateway 的超时内；运行前先停止写入
+        Some("rebuild-counts") => {
+            if !config.events.enabled {
+                return Err(LambdaError::FeatureDisabled("events".into()).into());
+            }
+            let report = events::rebuild_counts(storage.as_ref(), &config).await?;
+            println!("{}", json!(report));
+            return Ok(());
+        }
+        _ => {}
+    }
+
     // 使用block_in_place加载模型
-    let sentiment_model = tokio::task::block_in_place(|| {
-        SentimentModel::new(Default::default()).expect("Failed to load the sentiment model")
+    let load_start = Instant::now();
+    let classifier = match tokio::task::block_in_place(|| classifier::from_config(&config.model)) {
+        Ok(classifier) => classifier,
+        Err(e) => {
+            tracing::error!("Failed to load the sentiment model: {}", e);
+            return Err(e.into());
+        }
+    };
+    let model_status = ModelStatus {
+        model: config.model.id().to_string(),
+        backend: classifier.backend(),
+        loaded: true,
+        load_time_ms: load_start.elapsed().as_millis(),
+        loaded_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+    tracing::info!("Loaded model {} ({}) in {} ms", model_status.model, model_status.backend, model_status.load_time_ms);
+
+    // 模型、配置和存储客户端只在冷启动时构建一次，热调用直接复用
+    let state = Arc::new(AppState {
+        classifier,
+        model_status,
+        buffer: WriteBuffer::new(&config.buffer),
+        outbox: Outbox::new(&config.outbox),
+        config,
+        storage,
     });
-    let sentiment_model = Arc::new(Mutex::new(sentiment_model));
 
-    run(service_fn(move |req| function_handler(req, Arc::clone(&sentiment_model)))).await
+    // 实例关闭前 (SIGTERM) 写入缓冲中剩余的增量
+    let shutdown_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        let mut sigterm = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
+            Err(e) => {
+                tracing::warn!("Failed to install SIGTERM handler: {}", e);
+                return;
+            }
+        };
+        sigterm.recv().await;
+        let pending = shutdown_state.buffer.take();
+        tracing::info!(requests = pending.len(), "Shutting down, flushing buffered counts");
+        flush_counts(&shutdown_state, pending).await;
+        std::process::exit(0);
+    });
+
+    run(service_fn(move |req| function_handler(req, Arc::clone(&state)))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::StorageErrorKind;
+    use storage::testing::FaultStorage;
+    use storage::MemoryStorage;
+
+    // 用 mock 推理后端和内存存储构建状态，不需要下载模型
+    fn test_state() -> AppState {
+        let mut config = AppConfig::default();
+        config.model.backend = config::ModelBackend::Mock;
+        config.features.batch = true;
+        config.outbox.enabled = false;
+        AppState {
+            classifier: Arc::new(classifier::MockClassifier),
+            model_status: ModelStatus {
+                model: config.model.id().to_string(),
+                backend: "mock",
+                loaded: true,
+                load_time_ms: 0,
+                loaded_at: 0,
+            },
+            buffer: WriteBuffer::new(&config.buffer),
+            outbox: Outbox::new(&config.outbox),
+            storage: Arc::new(MemoryStorage::new()),
+            config,
+        }
+    }
+
+    fn input(command: &str, text: &str, texts: &[&str]) -> LambdaInput {
+        LambdaInput {
+            command: command.to_string(),
+            text: text.to_string(),
+            texts: texts.iter().map(|text| text.to_string()).collect(),
+            legacy: None,
+            tags: Vec::new(),
+            namespace: None,
+        }
+    }
+
+    async fn stored_totals(state: &AppState) -> BTreeMap<String, i32> {
+        let records = aggregate::read_merged_records(state.storage.as_ref(), &state.config.storage.key).await.unwrap();
+        aggregate::all_time_totals(&aggregate::fold_records(records))
+    }
+
+    #[tokio::test]
+    async fn test_process_input_with_mock_classifier() {
+        let state = test_state();
+        let output = process_input(input("sentiment", "I love this", &[]), "req-1".to_string(), &state).await.unwrap();
+        let sentiment = output.sentiment.unwrap();
+        assert_eq!(sentiment.polarity, Polarity::Positive);
+        assert!(output.persisted);
+        // mock 后端不报告配置中的真实模型名
+        assert_eq!(output.model, config::MOCK_MODEL_ID);
+
+        let output = process_input(input("sentiment_batch", "", &["great", "awful"]), "req-2".to_string(), &state).await.unwrap();
+        assert_eq!(output.results.len(), 2);
+        assert_eq!(output.results[1].polarity, Polarity::Negative);
+
+        let totals = stored_totals(&state).await;
+        assert_eq!(totals["Positive"], 2);
+        assert_eq!(totals["Negative"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_result_defaults_to_config() {
+        let mut state = test_state();
+        let output = process_input(input("sentiment", "great", &[]), "req-1".to_string(), &state).await.unwrap();
+        assert!(output.result.unwrap().starts_with("Sentiment: "));
+
+        // 请求中的 legacy 覆盖配置
+        let mut opt_out = input("sentiment", "great", &[]);
+        opt_out.legacy = Some(false);
+        assert!(process_input(opt_out, "req-2".to_string(), &state).await.unwrap().result.is_none());
+
+        state.config.features.legacy_result = false;
+        assert!(process_input(input("sentiment", "great", &[]), "req-3".to_string(), &state).await.unwrap().result.is_none());
+        let mut opt_in = input("sentiment", "great", &[]);
+        opt_in.legacy = Some(true);
+        assert!(process_input(opt_in, "req-4".to_string(), &state).await.unwrap().result.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_process_input_rejects_invalid_input() {
+        let state = test_state();
+        let err = process_input(input("translate", "hi", &[]), "req-1".to_string(), &state).await.unwrap_err();
+        assert!(matches!(err, LambdaError::InvalidCommand));
+        let err = process_input(input("sentiment_batch", "", &[]), "req-2".to_string(), &state).await.unwrap_err();
+        assert!(matches!(err, LambdaError::InvalidInput(_)));
+        // 空文本和只有空白的文本不送入模型
+        for text in ["", "  \n\t"] {
+            let err = process_input(input("sentiment", text, &[]), "req-3".to_string(), &state).await.unwrap_err();
+            assert!(matches!(&err, LambdaError::InvalidInput(message) if message == "text must not be empty"), "{:?}", err);
+        }
+        let err = process_input(input("sentiment_batch", "", &["great", " "]), "req-4".to_string(), &state).await.unwrap_err();
+        assert!(matches!(&err, LambdaError::InvalidInput(message) if message == "texts[1] must not be empty"), "{:?}", err);
+        // 无效请求不更新计数
+        assert_eq!(stored_totals(&state).await["Positive"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_storage_outage_spools_increments_and_replays() {
+        let dir = std::env::temp_dir().join(format!("rust_lambda_hf_main_outbox_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut state = test_state();
+        state.config.outbox.enabled = true;
+        state.config.outbox.dir = dir.clone();
+        state.config.events.enabled = true;
+        state.outbox = Outbox::new(&state.config.outbox);
+        let storage = Arc::new(FaultStorage::new());
+        state.storage = storage.clone();
+
+        // 存储不可用时仍然返回分析结果，增量进入 outbox
+        storage.fail_all(StorageErrorKind::Network);
+        let output = process_input(input("sentiment", "I love this", &[]), "req-1".to_string(), &state).await.unwrap();
+        assert_eq!(output.sentiment.unwrap().polarity, Polarity::Positive);
+        assert!(!output.persisted);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // 恢复后的下一次请求写入自己的增量并重放 outbox
+        storage.recover();
+        let output = process_input(input("sentiment", "awful", &[]), "req-2".to_string(), &state).await.unwrap();
+        assert!(output.persisted);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let totals = stored_totals(&state).await;
+        assert_eq!(totals["Positive"], 1);
+        assert_eq!(totals["Negative"], 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_instance_flushes_expired_buffer() {
+        let mut state = test_state();
+        state.config.buffer = config::BufferConfig { max_requests: 10, max_age_ms: 20, ..config::BufferConfig::default() };
+        state.buffer = WriteBuffer::new(&state.config.buffer);
+
+        let output = process_input(input("sentiment", "great", &[]), "req-1".to_string(), &state).await.unwrap();
+        assert_eq!(output.counts, CountStatus::Buffered);
+        assert!(!output.persisted);
+        assert_eq!(stored_totals(&state).await["Positive"], 0);
+
+        // 实例空闲超过 max_age_ms 后，下一次读写计数的调用开始时写入缓冲
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+        flush_expired(&state).await;
+        assert_eq!(stored_totals(&state).await["Positive"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_buffered_counts_before_rebuild_are_not_counted_twice() {
+        let mut state = test_state();
+        state.config.events.enabled = true;
+        state.config.buffer = config::BufferConfig { max_requests: 10, max_age_ms: 60_000, ..config::BufferConfig::default() };
+        state.buffer = WriteBuffer::new(&state.config.buffer);
+
+        // 事件已写入日志、增量还在缓冲中时重建
+        let output = process_input(input("sentiment", "great", &[]), "req-1".to_string(), &state).await.unwrap();
+        assert_eq!(output.counts, CountStatus::Buffered);
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        events::rebuild_counts(state.storage.as_ref(), &state.config).await.unwrap();
+        assert_eq!(stored_totals(&state).await["Positive"], 1);
+
+        // 重建之后的请求照常计数，重建前的缓冲增量被跳过
+        process_input(input("sentiment", "awful", &[]), "req-2".to_string(), &state).await.unwrap();
+        flush_counts(&state, state.buffer.take()).await;
+        let totals = stored_totals(&state).await;
+        assert_eq!(totals["Positive"], 1);
+        assert_eq!(totals["Negative"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_missing_bucket_is_not_initialized() {
+        let mut state = test_state();
+        // 桶已被删除：GetObject 返回 NoSuchBucket，HEAD 只返回没有错误码的 404
+        let storage = Arc::new(FaultStorage::new());
+        storage.fail_all(StorageErrorKind::NoSuchBucket);
+        storage.fail("head", StorageErrorKind::NotFound);
+        storage.fail("head_bucket", StorageErrorKind::NotFound);
+        state.storage = storage.clone();
+
+        let response = readiness_handler(&state.model_status, &state.config, state.storage.as_ref()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // 桶不存在时不会去创建初始计数对象
+        let output = process_input(input("sentiment", "great", &[]), "req-1".to_string(), &state).await.unwrap();
+        assert!(!output.persisted);
+        assert_eq!(storage.puts(), 0);
+
+        let response = readiness_handler(&state.model_status, &state.config, &MemoryStorage::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_admin_routes_require_token() {
+        let mut config = AppConfig::default();
+        config.features.admin = true;
+        config.server.admin_token = Some("secret".to_string());
+        let request = |token: Option<&str>| {
+            let mut builder = http::Request::builder().uri("/mini10-rust-hf-lambda/admin/compact");
+            if let Some(token) = token {
+                builder = builder.header("x-admin-token", token);
+            }
+            builder.body(Body::Empty).unwrap()
+        };
+
+        assert!(check_admin(&request(Some("secret")), &config).is_ok());
+        for token in [None, Some("wrong"), Some("secret2")] {
+            let err = check_admin(&request(token), &config).unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::UNAUTHORIZED);
+        }
+        config.features.admin = false;
+        assert!(matches!(check_admin(&request(Some("secret")), &config), Err(LambdaError::FeatureDisabled(_))));
+    }
+
+    fn response_json(response: &Response<Body>) -> serde_json::Value {
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    fn get(uri: &str) -> Request {
+        http::Request::builder().uri(uri).body(Body::Empty).unwrap()
+    }
+
+    #[test]
+    fn test_compute_stats_percentages() {
+        let mut positive = Aggregate::default();
+        for _ in 0..3 {
+            positive.observe(Some(0.9));
+        }
+        // 旧数据只有计数，没有分数
+        let mut negative = Aggregate::default();
+        negative.observe(None);
+        let stats = compute_stats(BTreeMap::from([("Positive".to_string(), positive), ("Negative".to_string(), negative)]));
+
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.polarities["Positive"].percentage, 75.0);
+        assert_eq!(stats.polarities["Negative"].percentage, 25.0);
+        assert!((stats.polarities["Positive"].mean_score.unwrap() - 0.9).abs() < 1e-9);
+        assert_eq!(stats.polarities["Negative"].scored, 0);
+        assert_eq!(stats.polarities["Negative"].mean_score, None);
+    }
+
+    #[test]
+    fn test_compute_stats_zero_total() {
+        let stats = compute_stats(BTreeMap::from([("Positive".to_string(), Aggregate::default()), ("Negative".to_string(), Aggregate::default())]));
+        assert_eq!(stats.total, 0);
+        // 没有数据时占比为 0，而不是 NaN
+        for polarity in stats.polarities.values() {
+            assert_eq!(polarity.percentage, 0.0);
+        }
+    }
+
+    fn range(params: &[(&str, &str)]) -> Result<Option<RangeQuery>, LambdaError> {
+        let query_map: HashMap<String, String> = params.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        parse_range_query(&query_map)
+    }
+
+    #[test]
+    fn test_parse_range_query() {
+        assert!(range(&[]).unwrap().is_none());
+
+        let query = range(&[("from", "2024-04-01"), ("to", "2024-04-03")]).unwrap().unwrap();
+        assert_eq!(query.granularity, Granularity::Day);
+        assert_eq!((query.from.as_str(), query.to.as_str()), ("2024-04-01", "2024-04-03"));
+
+        // 按小时时日期边界扩展到整天
+        let query = range(&[("granularity", "hour"), ("from", "2024-04-01"), ("to", "2024-04-01")]).unwrap().unwrap();
+        assert_eq!((query.from.as_str(), query.to.as_str()), ("2024-04-01T00", "2024-04-01T23"));
+    }
+
+    #[test]
+    fn test_parse_range_query_rejects_bad_ranges() {
+        let message = |params: &[(&str, &str)]| match range(params) {
+            Err(LambdaError::InvalidInput(message)) => message,
+            other => panic!("unexpected result: {:?}", other.map(|query| query.is_some())),
+        };
+        // 只有 granularity 没有 from / to
+        assert_eq!(message(&[("granularity", "day")]), "Missing from parameter");
+        assert_eq!(message(&[("granularity", "hour"), ("from", "2024-04-01T05")]), "Missing to parameter");
+        assert_eq!(message(&[("from", "2024-04-03"), ("to", "2024-04-01")]), "from must not be after to");
+        assert!(message(&[("from", "2024/04/01"), ("to", "2024-04-03")]).starts_with("Invalid from"));
+        assert!(message(&[("granularity", "week"), ("from", "2024-04-01"), ("to", "2024-04-03")]).starts_with("Unknown granularity"));
+    }
+
+    #[tokio::test]
+    async fn test_stats_handler() {
+        let mut state = test_state();
+        state.config.namespaces.allowed = vec!["shop".to_string()];
+        for text in ["great", "love it", "awful"] {
+            process_input(input("sentiment", text, &[]), "req-1".to_string(), &state).await.unwrap();
+        }
+        let mut scoped = input("sentiment", "awful", &[]);
+        scoped.namespace = Some("shop".to_string());
+        process_input(scoped, "req-2".to_string(), &state).await.unwrap();
+
+        let response = stats_handler(&get("/mini10-rust-hf-lambda/stats"), "req-3", &state.config, state.storage.as_ref()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_json(&response);
+        assert_eq!(body["total"], 3);
+        assert_eq!(body["polarities"]["Positive"]["count"], 2);
+
+        let response = stats_handler(&get("/mini10-rust-hf-lambda/stats?namespace=shop"), "req-4", &state.config, state.storage.as_ref()).await;
+        let body = response_json(&response);
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["polarities"]["Negative"]["percentage"], 100.0);
+
+        // 不在白名单中的 namespace
+        let response = stats_handler(&get("/mini10-rust-hf-lambda/stats?namespace=other"), "req-5", &state.config, state.storage.as_ref()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response_json(&response)["error"]["code"], "invalid_input");
+
+        state.config.features.stats = false;
+        let response = stats_handler(&get("/mini10-rust-hf-lambda/stats"), "req-6", &state.config, state.storage.as_ref()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response_json(&response)["error"]["code"], "feature_disabled");
+    }
+
+    fn post(content_type: Option<&str>, body: &str) -> Request {
+        let mut builder = http::Request::builder().method(http::Method::POST).uri("/mini10-rust-hf-lambda");
+        if let Some(content_type) = content_type {
+            builder = builder.header(http::header::CONTENT_TYPE, content_type);
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[test]
+    fn test_parse_input_get_query() {
+        let request = http::Request::builder()
+            .uri("/mini10-rust-hf-lambda?command=sentiment&text=I%20love%20this&legacy=1&tags=web,%20beta&namespace=shop")
+            .body(Body::Empty)
+            .unwrap();
+        let input = parse_input(&request).unwrap();
+        assert_eq!(input.command, "sentiment");
+        assert_eq!(input.text, "I love this");
+        assert_eq!(input.legacy, Some(true));
+        assert_eq!(input.tags, ["web", "beta"]);
+        assert_eq!(input.namespace.as_deref(), Some("shop"));
+
+        // command 可选，缺少 text 时拒绝
+        let request = http::Request::builder().uri("/mini10-rust-hf-lambda?text=ok").body(Body::Empty).unwrap();
+        assert_eq!(parse_input(&request).unwrap().command, "sentiment");
+        let request = http::Request::builder().uri("/mini10-rust-hf-lambda?command=sentiment").body(Body::Empty).unwrap();
+        assert!(matches!(parse_input(&request), Err(LambdaError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_parse_input_post_bodies() {
+        let input = parse_input(&post(Some("application/json"), r#"{"command":"sentiment_batch","texts":["great","awful"]}"#)).unwrap();
+        assert_eq!(input.command, "sentiment_batch");
+        assert_eq!(input.texts, ["great", "awful"]);
+
+        let input = parse_input(&post(Some("application/x-www-form-urlencoded; charset=utf-8"), "text=I+love+this&legacy=false")).unwrap();
+        assert_eq!(input.command, "sentiment");
+        assert_eq!(input.text, "I love this");
+        assert_eq!(input.legacy, Some(false));
+
+        // 没有 Content-Type 时按 JSON 解析
+        let input = parse_input(&post(None, r#"{"text":"great"}"#)).unwrap();
+        assert_eq!(input.text, "great");
+        assert!(matches!(parse_input(&post(None, "text=great")), Err(LambdaError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_parse_input_rejects_bad_bodies() {
+        for (content_type, body) in [
+            (Some("application/json"), ""),
+            (Some("application/json"), "{\"text\": "),
+            (Some("application/json"), "[1, 2]"),
+        ] {
+            let err = parse_input(&post(content_type, body)).unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::BAD_REQUEST, "{:?}", body);
+        }
+        let err = parse_input(&post(Some("application/json"), "")).unwrap_err();
+        assert!(matches!(&err, LambdaError::InvalidInput(message) if message == "Missing request body"), "{:?}", err);
+    }
 }
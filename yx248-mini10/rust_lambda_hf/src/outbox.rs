@@ -0,0 +1,247 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+
+use crate::aggregate::{self, Counts, SentimentRecord};
+use crate::config::{AppConfig, OutboxConfig};
+use crate::error::LambdaError;
+use crate::events;
+use crate::storage::{io_error_kind, Storage};
+
+// outbox 中的一个文件：一次未能写入的计数增量
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OutboxEntry {
+    pub key: String,
+    pub created_at: DateTime<Utc>,
+    pub records: Vec<SentimentRecord>,
+    // 这些增量的事件写入日志的时间 (最晚的一条)：早于之后的重建时已被计入，重放时跳过
+    #[serde(default)]
+    pub logged_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct DrainReport {
+    pub applied: usize,
+    pub remaining: usize,
+    // 无法解析、被改名为 .dead 的文件
+    pub dead_lettered: usize,
+    // 已被之后的重建计入、直接删除的文件
+    pub skipped: usize,
+}
+
+fn io_error(operation: &'static str, path: &Path, err: std::io::Error) -> LambdaError {
+    LambdaError::storage("outbox", operation, &path.display().to_string(), io_error_kind(&err), err)
+}
+
+// 本地目录中的待重试增量；同一个 Lambda 实例的后续调用负责重放
+pub struct Outbox {
+    dir: PathBuf,
+    enabled: bool,
+    max_entries: usize,
+    drain_batch: usize,
+    // 同一实例中并发的调用不能重复重放同一个文件
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl Outbox {
+    pub fn new(config: &OutboxConfig) -> Self {
+        Outbox {
+            dir: config.dir.clone(),
+            enabled: config.enabled,
+            max_entries: config.max_entries,
+            drain_batch: config.drain_batch,
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    // 按文件名排序即按写入时间排序
+    async fn entries(&self) -> Result<Vec<PathBuf>, LambdaError> {
+        let mut dir = match tokio::fs::read_dir(&self.dir).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(io_error("list", &self.dir, e)),
+        };
+        let mut paths = Vec::new();
+        while let Some(entry) = dir.next_entry().await.map_err(|e| io_error("list", &self.dir, e))? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    // 保存一次失败的增量；先写临时文件再 rename，重放时不会读到写了一半的文件
+    pub async fn push(&self, key: &str, increments: &Counts, logged_at: Option<DateTime<Utc>>) -> Result<(), LambdaError> {
+        if !self.enabled {
+            return Err(LambdaError::InternalError("Outbox is disabled".into()));
+        }
+        let _guard = self.lock.lock().await;
+        tokio::fs::create_dir_all(&self.dir).await.map_err(|e| io_error("put", &self.dir, e))?;
+        if self.entries().await?.len() >= self.max_entries {
+            return Err(LambdaError::InternalError(format!("Outbox is full ({} entries)", self.max_entries)));
+        }
+
+        let now = Utc::now();
+        let entry = OutboxEntry {
+            key: key.to_string(),
+            created_at: now,
+            records: aggregate::to_records(increments),
+            logged_at,
+        };
+        let data = serde_json::to_vec(&entry)
+            .map_err(|e| LambdaError::InternalError(format!("Failed to serialize outbox entry: {}", e)))?;
+        let random = RandomState::new().build_hasher().finish() as u32;
+        let name = format!("{:020}-{:08x}", now.timestamp_micros(), random);
+        let path = self.dir.join(format!("{}.json", name));
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        tokio::fs::write(&tmp, data).await.map_err(|e| io_error("put", &tmp, e))?;
+        tokio::fs::rename(&tmp, &path).await.map_err(|e| io_error("put", &path, e))?;
+        tracing::warn!(key, path = %path.display(), "Spooled counter increment to outbox");
+        Ok(())
+    }
+
+    // 按写入顺序重放最多 drain_batch 个文件；存储写入失败时停止，剩下的留到下次调用。
+    // 无法解析的文件改名为 .dead 留待人工检查，不阻塞后面的文件
+    pub async fn drain(&self, storage: &dyn Storage, config: &AppConfig) -> Result<DrainReport, LambdaError> {
+        if !self.enabled {
+            return Ok(DrainReport::default());
+        }
+        // 其他调用正在重放时直接跳过，不阻塞当前请求
+        let Ok(_guard) = self.lock.try_lock() else {
+            return Ok(DrainReport::default());
+        };
+        let paths = self.entries().await?;
+        let mut report = DrainReport {
+            remaining: paths.len(),
+            ..DrainReport::default()
+        };
+        if paths.is_empty() {
+            return Ok(report);
+        }
+        let rebuilt_at = events::rebuilt_at(storage, config).await?;
+
+        for path in paths.into_iter().take(self.drain_batch) {
+            let data = tokio::fs::read(&path).await.map_err(|e| io_error("get", &path, e))?;
+            let entry: OutboxEntry = match serde_json::from_slice(&data) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let dead = path.with_extension("dead");
+                    tokio::fs::rename(&path, &dead).await.map_err(|e| io_error("put", &dead, e))?;
+                    tracing::error!(path = %dead.display(), error = %e, "Moved unparsable outbox entry to dead letter");
+                    report.dead_lettered += 1;
+                    report.remaining -= 1;
+                    continue;
+                }
+            };
+            if events::counted_by_rebuild(entry.logged_at, rebuilt_at) {
+                tokio::fs::remove_file(&path).await.map_err(|e| io_error("delete", &path, e))?;
+                tracing::info!(key = entry.key.as_str(), path = %path.display(), "Skipped outbox entry already counted by rebuild");
+                report.skipped += 1;
+                report.remaining -= 1;
+                continue;
+            }
+            let increments = aggregate::fold_records(entry.records);
+            aggregate::update_sentiment_count(storage, config, &entry.key, &increments).await?;
+            tokio::fs::remove_file(&path).await.map_err(|e| io_error("delete", &path, e))?;
+            report.applied += 1;
+            report.remaining -= 1;
+        }
+        if report.applied > 0 {
+            tracing::info!(applied = report.applied, remaining = report.remaining, "Replayed outbox entries");
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_push_and_drain_in_batches() {
+        let dir = std::env::temp_dir().join(format!("rust_lambda_hf_outbox_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = AppConfig::default();
+        let outbox = Outbox::new(&OutboxConfig {
+            dir: dir.clone(),
+            drain_batch: 1,
+            ..OutboxConfig::default()
+        });
+        let storage = MemoryStorage::new();
+        for sentiment in ["Positive", "Negative"] {
+            let mut increments = Counts::new();
+            aggregate::add_increment(&mut increments, sentiment, Some(0.9), Utc::now());
+            outbox.push("sentiment.csv", &increments, Some(Utc::now())).await.unwrap();
+        }
+
+        let report = outbox.drain(&storage, &config).await.unwrap();
+        assert_eq!(report, DrainReport { applied: 1, remaining: 1, ..DrainReport::default() });
+        let report = outbox.drain(&storage, &config).await.unwrap();
+        assert_eq!(report, DrainReport { applied: 1, remaining: 0, ..DrainReport::default() });
+        assert_eq!(outbox.drain(&storage, &config).await.unwrap(), DrainReport::default());
+
+        let counts = aggregate::fold_records(aggregate::read_merged_records(&storage, "sentiment.csv").await.unwrap());
+        let totals = aggregate::all_time_totals(&counts);
+        assert_eq!(totals["Positive"], 1);
+        assert_eq!(totals["Negative"], 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unparsable_entry_is_dead_lettered() {
+        let dir = std::env::temp_dir().join(format!("rust_lambda_hf_outbox_dead_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = AppConfig::default();
+        let outbox = Outbox::new(&OutboxConfig {
+            dir: dir.clone(),
+            ..OutboxConfig::default()
+        });
+        let storage = MemoryStorage::new();
+        std::fs::create_dir_all(&dir).unwrap();
+        // 文件名排在最前面，先于正常的条目被读取
+        std::fs::write(dir.join("00000000000000000000-00000000.json"), b"{not json").unwrap();
+        let mut increments = Counts::new();
+        aggregate::add_increment(&mut increments, "Positive", Some(0.9), Utc::now());
+        outbox.push("sentiment.csv", &increments, Some(Utc::now())).await.unwrap();
+
+        let report = outbox.drain(&storage, &config).await.unwrap();
+        assert_eq!(report, DrainReport { applied: 1, dead_lettered: 1, ..DrainReport::default() });
+        assert!(dir.join("00000000000000000000-00000000.dead").exists());
+        let counts = aggregate::fold_records(aggregate::read_merged_records(&storage, "sentiment.csv").await.unwrap());
+        assert_eq!(aggregate::all_time_totals(&counts)["Positive"], 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_entries_counted_by_rebuild_are_skipped() {
+        let dir = std::env::temp_dir().join(format!("rust_lambda_hf_outbox_rebuild_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = AppConfig::default();
+        let outbox = Outbox::new(&OutboxConfig {
+            dir: dir.clone(),
+            ..OutboxConfig::default()
+        });
+        let storage = MemoryStorage::new();
+        for (sentiment, logged) in [("Positive", true), ("Negative", false)] {
+            let mut increments = Counts::new();
+            aggregate::add_increment(&mut increments, sentiment, Some(0.9), Utc::now());
+            outbox.push("sentiment.csv", &increments, logged.then(Utc::now)).await.unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        events::rebuild_counts(&storage, &config).await.unwrap();
+
+        // 事件已在日志中的增量由重建计入；没有写入日志的增量仍要重放
+        let report = outbox.drain(&storage, &config).await.unwrap();
+        assert_eq!(report, DrainReport { applied: 1, skipped: 1, ..DrainReport::default() });
+        let counts = aggregate::fold_records(aggregate::read_merged_records(&storage, "sentiment.csv").await.unwrap());
+        let totals = aggregate::all_time_totals(&counts);
+        assert_eq!(totals["Positive"], 0);
+        assert_eq!(totals["Negative"], 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
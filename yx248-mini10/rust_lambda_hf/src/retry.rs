@@ -0,0 +1,271 @@
+use async_trait::async_trait;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::RetryConfig;
+use crate::error::{LambdaError, StorageErrorKind};
+use crate::storage::{Storage, StoredObject, WriteCondition};
+
+#[derive(Default)]
+struct BreakerState {
+    // 连续失败的存储调用次数
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+// 熔断器：连续 threshold 次调用因临时错误失败后打开，cooldown 内的调用不再发送到存储
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    fn new(config: &RetryConfig) -> Self {
+        CircuitBreaker {
+            threshold: config.breaker_threshold,
+            cooldown: Duration::from_millis(config.breaker_cooldown_ms),
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    fn allow(&self) -> bool {
+        if self.threshold == 0 {
+            return true;
+        }
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        match state.open_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                // 冷却结束：放行试探请求，再失败一次就重新打开
+                state.open_until = None;
+                state.failures = self.threshold - 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        state.failures = 0;
+        state.open_until = None;
+    }
+
+    fn record_failure(&self, backend: &'static str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        state.failures += 1;
+        if state.failures >= self.threshold && state.open_until.is_none() {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            tracing::error!(backend, failures = state.failures, cooldown_ms = self.cooldown.as_millis() as u64, "Storage circuit breaker opened");
+        }
+    }
+}
+
+// 在任意存储后端外层加上重试和熔断，调用方不需要关心临时错误
+pub struct RetryingStorage {
+    inner: Arc<dyn Storage>,
+    config: RetryConfig,
+    breaker: CircuitBreaker,
+}
+
+impl RetryingStorage {
+    pub fn new(inner: Arc<dyn Storage>, config: RetryConfig) -> Self {
+        RetryingStorage {
+            breaker: CircuitBreaker::new(&config),
+            inner,
+            config,
+        }
+    }
+
+    fn error(&self, operation: &'static str, key: &str, kind: StorageErrorKind, message: String) -> LambdaError {
+        LambdaError::storage(self.inner.backend(), operation, key, kind, std::io::Error::other(message))
+    }
+
+    // 第 attempt 次失败后的等待时间：在 [0, min(max_delay, base_delay * 2^(attempt-1))] 之间随机 (full jitter)
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .config
+            .base_delay_ms
+            .saturating_mul(1u64 << (attempt - 1).min(20))
+            .min(self.config.max_delay_ms);
+        let random = RandomState::new().build_hasher().finish();
+        Duration::from_millis(random % (ceiling + 1))
+    }
+
+    // retry_ambiguous 为 false 时只重试限流：超时、网络或服务端错误时写入可能已经生效，
+    // 带条件的写入再试一次会把自己的写入当成并发冲突，交给调用方重新读取
+    async fn call<T, F, Fut>(&self, operation: &'static str, key: &str, retry_ambiguous: bool, mut request: F) -> Result<T, LambdaError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, LambdaError>>,
+    {
+        if !self.breaker.allow() {
+            return Err(self.error(operation, key, StorageErrorKind::CircuitOpen, "circuit breaker is open".into()));
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(self.config.deadline_ms);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let result = match tokio::time::timeout(remaining, request()).await {
+                Ok(result) => result,
+                Err(_) => Err(self.error(
+                    operation,
+                    key,
+                    StorageErrorKind::Timeout,
+                    format!("deadline of {} ms exceeded", self.config.deadline_ms),
+                )),
+            };
+            let err = match result {
+                Ok(value) => {
+                    self.breaker.record_success();
+                    return Ok(value);
+                }
+                Err(err) => err,
+            };
+
+            let kind = err.storage_kind();
+            if !kind.is_some_and(|kind| kind.is_transient()) {
+                // 对象不存在、条件不满足等错误说明存储本身是可用的
+                self.breaker.record_success();
+                return Err(err);
+            }
+            let retryable = retry_ambiguous || kind == Some(StorageErrorKind::Throttling);
+            let delay = self.backoff(attempt);
+            if !retryable || attempt >= self.config.max_attempts || Instant::now() + delay >= deadline {
+                self.breaker.record_failure(self.inner.backend());
+                return Err(err);
+            }
+            tracing::info!(operation, key, attempt, delay_ms = delay.as_millis() as u64, "Retrying storage request");
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for RetryingStorage {
+    fn backend(&self) -> &'static str {
+        self.inner.backend()
+    }
+
+    fn location(&self, key: &str) -> String {
+        self.inner.location(key)
+    }
+
+    async fn get(&self, key: &str) -> Result<StoredObject, LambdaError> {
+        self.call("get", key, true, || self.inner.get(key)).await
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>, condition: WriteCondition) -> Result<(), LambdaError> {
+        let retry_ambiguous = condition == WriteCondition::Always;
+        self.call("put", key, retry_ambiguous, || self.inner.put(key, data.clone(), condition.clone())).await
+    }
+
+    async fn head(&self, key: &str) -> Result<(), LambdaError> {
+        self.call("head", key, true, || self.inner.head(key)).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, LambdaError> {
+        self.call("list", prefix, true, || self.inner.list(prefix)).await
+    }
+
+    async fn head_bucket(&self) -> Result<(), LambdaError> {
+        self.call("head_bucket", "", true, || self.inner.head_bucket()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::FaultStorage;
+
+    fn retry_config(max_attempts: u32, breaker_threshold: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            base_delay_ms: 1,
+            max_delay_ms: 2,
+            deadline_ms: 1_000,
+            breaker_threshold,
+            breaker_cooldown_ms: 50,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() {
+        let faulty = Arc::new(FaultStorage::new().with_faults(&[StorageErrorKind::Throttling, StorageErrorKind::ServerError, StorageErrorKind::Network]));
+        let storage = RetryingStorage::new(faulty.clone(), retry_config(4, 0));
+        storage.put("sentiment.csv", b"v1".to_vec(), WriteCondition::Always).await.unwrap();
+        assert_eq!(faulty.calls(), 4);
+
+        // 不存在的对象不重试
+        let err = storage.get("missing.csv").await.unwrap_err();
+        assert_eq!(err.storage_kind(), Some(StorageErrorKind::NoSuchKey));
+        assert_eq!(faulty.calls(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let faulty = Arc::new(FaultStorage::new().with_faults(&[StorageErrorKind::Throttling; 5]));
+        let storage = RetryingStorage::new(faulty.clone(), retry_config(3, 0));
+        let err = storage.list("events/").await.unwrap_err();
+        assert_eq!(err.storage_kind(), Some(StorageErrorKind::Throttling));
+        assert_eq!(faulty.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_put_not_retried_on_ambiguous_errors() {
+        let faulty = Arc::new(FaultStorage::new().with_faults(&[StorageErrorKind::Timeout, StorageErrorKind::Throttling]));
+        let storage = RetryingStorage::new(faulty.clone(), retry_config(3, 0));
+        let err = storage.put("sentiment.csv", b"v1".to_vec(), WriteCondition::IfAbsent).await.unwrap_err();
+        assert_eq!(err.storage_kind(), Some(StorageErrorKind::Timeout));
+        assert_eq!(faulty.calls(), 1);
+
+        // 限流说明请求没有被处理，可以安全重试
+        storage.put("sentiment.csv", b"v1".to_vec(), WriteCondition::IfAbsent).await.unwrap();
+        assert_eq!(faulty.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_deadline_bounds_slow_requests() {
+        let faulty = Arc::new(FaultStorage::new().with_delay(Duration::from_millis(200)));
+        let storage = RetryingStorage::new(faulty.clone(), RetryConfig { deadline_ms: 20, ..retry_config(3, 0) });
+        let started = Instant::now();
+        let err = storage.head("sentiment.csv").await.unwrap_err();
+        assert_eq!(err.storage_kind(), Some(StorageErrorKind::Timeout));
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert_eq!(faulty.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_short_circuits() {
+        let faulty = Arc::new(FaultStorage::new().with_faults(&[StorageErrorKind::Timeout, StorageErrorKind::Timeout, StorageErrorKind::Timeout]));
+        let storage = RetryingStorage::new(faulty.clone(), retry_config(1, 2));
+        faulty.inner.put("sentiment.csv", b"v1".to_vec(), WriteCondition::Always).await.unwrap();
+
+        assert!(storage.get("sentiment.csv").await.is_err());
+        assert!(storage.get("sentiment.csv").await.is_err());
+        // 熔断打开后不再调用存储
+        let err = storage.get("sentiment.csv").await.unwrap_err();
+        assert_eq!(err.storage_kind(), Some(StorageErrorKind::CircuitOpen));
+        assert_eq!(faulty.calls(), 2);
+
+        // 冷却后放行试探请求，失败则重新打开
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(storage.get("sentiment.csv").await.unwrap_err().storage_kind(), Some(StorageErrorKind::Timeout));
+        assert_eq!(storage.get("sentiment.csv").await.unwrap_err().storage_kind(), Some(StorageErrorKind::CircuitOpen));
+
+        // 试探成功后关闭
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(storage.get("sentiment.csv").await.unwrap().data, b"v1".to_vec());
+        assert!(storage.get("sentiment.csv").await.is_ok());
+        assert_eq!(faulty.calls(), 5);
+    }
+}
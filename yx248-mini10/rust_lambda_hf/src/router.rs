@@ -0,0 +1,113 @@
+use lambda_http::http::Method;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Sentiment,
+    Stats,
+    Health,
+    Ready,
+    Version,
+    Compact,
+    Export,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RouteMatch {
+    Found(Route),
+    MethodNotAllowed(Route),
+    NotFound,
+}
+
+impl Route {
+    fn allows(&self, method: &Method) -> bool {
+        match self {
+            Route::Sentiment => method == Method::GET || method == Method::POST,
+            Route::Stats | Route::Health | Route::Ready | Route::Version => method == Method::GET,
+            Route::Compact | Route::Export => method == Method::POST,
+        }
+    }
+
+    // 405 响应中 Allow 头的值
+    pub fn allow_header(&self) -> &'static str {
+        match self {
+            Route::Sentiment => "GET, POST",
+            Route::Stats | Route::Health | Route::Ready | Route::Version => "GET",
+            Route::Compact | Route::Export => "POST",
+        }
+    }
+}
+
+// base_path 是 API Gateway 部署时请求路径带有的前缀 (server.base_path)，路由前去掉
+fn normalize_path<'a>(path: &'a str, base_path: &str) -> &'a str {
+    let path = match path.strip_prefix(base_path) {
+        // 只在路径段边界上去掉前缀
+        Some(rest) if !base_path.is_empty() && (rest.is_empty() || rest.starts_with('/')) => rest,
+        _ => path,
+    };
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+// 根据 method 和 path 找到对应的路由
+pub fn resolve(method: &Method, path: &str, base_path: &str) -> RouteMatch {
+    let route = match normalize_path(path, base_path) {
+        // "/" 保留给旧的 `?text=` 查询方式
+        "/" | "/sentiment" => Route::Sentiment,
+        "/stats" => Route::Stats,
+        // liveness 和 readiness 分开检查
+        "/health" | "/health/live" => Route::Health,
+        "/health/ready" => Route::Ready,
+        "/version" => Route::Version,
+        "/admin/compact" => Route::Compact,
+        "/admin/export" => Route::Export,
+        _ => return RouteMatch::NotFound,
+    };
+
+    if route.allows(method) {
+        RouteMatch::Found(route)
+    } else {
+        RouteMatch::MethodNotAllowed(route)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "/mini10-rust-hf-lambda";
+
+    #[test]
+    fn test_resolve_known_routes() {
+        assert_eq!(resolve(&Method::GET, "/", BASE), RouteMatch::Found(Route::Sentiment));
+        assert_eq!(resolve(&Method::POST, "/sentiment", BASE), RouteMatch::Found(Route::Sentiment));
+        assert_eq!(resolve(&Method::GET, "/stats", BASE), RouteMatch::Found(Route::Stats));
+        assert_eq!(resolve(&Method::GET, "/health/", BASE), RouteMatch::Found(Route::Health));
+        assert_eq!(resolve(&Method::GET, "/health/ready", BASE), RouteMatch::Found(Route::Ready));
+        assert_eq!(resolve(&Method::GET, "/version", BASE), RouteMatch::Found(Route::Version));
+        assert_eq!(resolve(&Method::POST, "/admin/compact", BASE), RouteMatch::Found(Route::Compact));
+        // 重建只能通过批处理命令运行
+        assert_eq!(resolve(&Method::POST, "/admin/rebuild", BASE), RouteMatch::NotFound);
+        assert_eq!(resolve(&Method::POST, "/admin/export", BASE), RouteMatch::Found(Route::Export));
+    }
+
+    #[test]
+    fn test_resolve_strips_base_path() {
+        assert_eq!(resolve(&Method::GET, "/mini10-rust-hf-lambda", BASE), RouteMatch::Found(Route::Sentiment));
+        assert_eq!(resolve(&Method::GET, "/mini10-rust-hf-lambda/version", BASE), RouteMatch::Found(Route::Version));
+        assert_eq!(resolve(&Method::GET, "/prod/version", "/prod"), RouteMatch::Found(Route::Version));
+        // 前缀必须是完整的路径段
+        assert_eq!(resolve(&Method::GET, "/mini10-rust-hf-lambdax/version", BASE), RouteMatch::NotFound);
+        assert_eq!(resolve(&Method::GET, "/version", ""), RouteMatch::Found(Route::Version));
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        assert_eq!(resolve(&Method::GET, "/unknown", BASE), RouteMatch::NotFound);
+        assert_eq!(resolve(&Method::DELETE, "/sentiment", BASE), RouteMatch::MethodNotAllowed(Route::Sentiment));
+        assert_eq!(resolve(&Method::POST, "/version", BASE), RouteMatch::MethodNotAllowed(Route::Version));
+    }
+}
@@ -0,0 +1,556 @@
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client as S3Client;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{StorageBackend, StorageConfig};
+use crate::error::{LambdaError, StorageErrorKind};
+use crate::retry::RetryingStorage;
+
+// 读取到的对象内容和版本标识 (S3 的 ETag)
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub data: Vec<u8>,
+    pub etag: Option<String>,
+}
+
+// 写入条件，用于 compare-and-swap
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteCondition {
+    Always,
+    // 对象的 ETag 仍然等于读取时的值才写入
+    IfMatch(String),
+    // 对象不存在时才写入
+    IfAbsent,
+}
+
+impl WriteCondition {
+    pub fn from_etag(etag: Option<String>) -> Self {
+        etag.map_or(WriteCondition::Always, WriteCondition::IfMatch)
+    }
+}
+
+// 本地和内存后端用内容的 hash 作为 ETag
+fn content_etag(data: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+fn precondition_failed(backend: &'static str, key: &str) -> LambdaError {
+    let err = std::io::Error::other(format!("{} was modified concurrently", key));
+    LambdaError::storage(backend, "put", key, StorageErrorKind::PreconditionFailed, err)
+}
+
+// 聚合数据 (sentiment.csv) 和事件对象的存储接口
+#[async_trait]
+pub trait Storage: Send + Sync {
+    fn backend(&self) -> &'static str;
+
+    // 用于日志和 health 输出的对象位置
+    fn location(&self, key: &str) -> String;
+
+    async fn get(&self, key: &str) -> Result<StoredObject, LambdaError>;
+
+    // 条件不满足时返回 StorageErrorKind::PreconditionFailed
+    async fn put(&self, key: &str, data: Vec<u8>, condition: WriteCondition) -> Result<(), LambdaError>;
+
+    // 只检查对象是否存在且可访问
+    async fn head(&self, key: &str) -> Result<(), LambdaError>;
+
+    // 列出以 prefix 开头的全部 key，按字典序排列
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, LambdaError>;
+
+    // 检查桶本身是否存在且可访问；本地和内存后端写入时会自动创建，默认总是成功
+    async fn head_bucket(&self) -> Result<(), LambdaError> {
+        Ok(())
+    }
+}
+
+pub struct S3Storage {
+    client: S3Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    pub fn new(client: S3Client, bucket: String) -> Self {
+        S3Storage { client, bucket }
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    fn backend(&self) -> &'static str {
+        "s3"
+    }
+
+    fn location(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket, key)
+    }
+
+    async fn get(&self, key: &str) -> Result<StoredObject, LambdaError> {
+        let output = self.client.get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| LambdaError::s3("GetObject", &self.bucket, key, e))?;
+
+        let etag = output.e_tag().map(str::to_string);
+        let bytes = output
+            .body
+            .collect()
+            .await
+            .map_err(|e| LambdaError::s3_other("GetObject", &self.bucket, key, e))?;
+
+        Ok(StoredObject {
+            data: bytes.into_bytes().to_vec(),
+            etag,
+        })
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>, condition: WriteCondition) -> Result<(), LambdaError> {
+        let request = self.client.put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(data));
+        // S3 conditional write：ETag 不匹配时返回 412 PreconditionFailed
+        let request = match condition {
+            WriteCondition::Always => request,
+            WriteCondition::IfMatch(etag) => request.if_match(etag),
+            WriteCondition::IfAbsent => request.if_none_match("*"),
+        };
+
+        request
+            .send()
+            .await
+            .map_err(|e| LambdaError::s3("PutObject", &self.bucket, key, e))?;
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<(), LambdaError> {
+        self.client.head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| LambdaError::s3("HeadObject", &self.bucket, key, e))?;
+        Ok(())
+    }
+
+    async fn head_bucket(&self) -> Result<(), LambdaError> {
+        self.client.head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await
+            .map_err(|e| LambdaError::s3("HeadBucket", &self.bucket, "", e))?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, LambdaError> {
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let output = self.client.list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token.take())
+                .send()
+                .await
+                .map_err(|e| LambdaError::s3("ListObjectsV2", &self.bucket, prefix, e))?;
+
+            keys.extend(output.contents().iter().filter_map(|object| object.key().map(str::to_string)));
+
+            match output.next_continuation_token() {
+                Some(token) if output.is_truncated() == Some(true) => continuation_token = Some(token.to_string()),
+                _ => break,
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+// 本地目录存储，key 对应目录下的相对路径
+// 条件写入只在同一进程内是原子的，适合本地开发
+pub struct LocalStorage {
+    root: PathBuf,
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        LocalStorage {
+            root,
+            write_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+pub(crate) fn io_error_kind(err: &std::io::Error) -> StorageErrorKind {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => StorageErrorKind::NoSuchKey,
+        std::io::ErrorKind::PermissionDenied => StorageErrorKind::AccessDenied,
+        _ => StorageErrorKind::Other,
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn backend(&self) -> &'static str {
+        "local"
+    }
+
+    fn location(&self, key: &str) -> String {
+        self.path(key).display().to_string()
+    }
+
+    async fn get(&self, key: &str) -> Result<StoredObject, LambdaError> {
+        let data = tokio::fs::read(self.path(key))
+            .await
+            .map_err(|e| LambdaError::storage("local", "read", key, io_error_kind(&e), e))?;
+        let etag = Some(content_etag(&data));
+        Ok(StoredObject { data, etag })
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>, condition: WriteCondition) -> Result<(), LambdaError> {
+        let _guard = self.write_lock.lock().await;
+        let path = self.path(key);
+
+        let current = tokio::fs::read(&path).await.ok().map(|data| content_etag(&data));
+        let satisfied = match &condition {
+            WriteCondition::Always => true,
+            WriteCondition::IfMatch(expected) => current.as_ref() == Some(expected),
+            WriteCondition::IfAbsent => current.is_none(),
+        };
+        if !satisfied {
+            return Err(precondition_failed("local", key));
+        }
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| LambdaError::storage("local", "create_dir", key, io_error_kind(&e), e))?;
+        }
+
+        // 先写临时文件再 rename，避免读到写了一半的文件
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, data)
+            .await
+            .map_err(|e| LambdaError::storage("local", "write", key, io_error_kind(&e), e))?;
+        tokio::fs::rename(&tmp_path, &path)
+            .await
+            .map_err(|e| LambdaError::storage("local", "rename", key, io_error_kind(&e), e))
+    }
+
+    async fn head(&self, key: &str) -> Result<(), LambdaError> {
+        tokio::fs::metadata(self.path(key))
+            .await
+            .map(|_| ())
+            .map_err(|e| LambdaError::storage("local", "metadata", key, io_error_kind(&e), e))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, LambdaError> {
+        // 从 prefix 中的目录部分开始递归遍历
+        let start = prefix.rsplit_once('/').map_or("", |(dir, _)| dir);
+        let mut pending = vec![start.to_string()];
+        let mut keys = Vec::new();
+
+        while let Some(dir) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(self.path(&dir)).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(LambdaError::storage("local", "read_dir", &dir, io_error_kind(&e), e)),
+            };
+
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .map_err(|e| LambdaError::storage("local", "read_dir", &dir, io_error_kind(&e), e))?
+            {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let key = if dir.is_empty() { name } else { format!("{}/{}", dir, name) };
+                let is_dir = entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false);
+                if is_dir {
+                    pending.push(key);
+                } else if key.starts_with(prefix) && !key.ends_with(".tmp") {
+                    keys.push(key);
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+// 内存存储，用于测试和无需持久化的本地运行
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    fn not_found(key: &str) -> LambdaError {
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, format!("no object at {}", key));
+        LambdaError::storage("memory", "get", key, StorageErrorKind::NoSuchKey, err)
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
+
+    fn location(&self, key: &str) -> String {
+        format!("memory://{}", key)
+    }
+
+    async fn get(&self, key: &str) -> Result<StoredObject, LambdaError> {
+        let objects = self.objects.lock().expect("memory storage lock poisoned");
+        let data = objects.get(key).cloned().ok_or_else(|| MemoryStorage::not_found(key))?;
+        let etag = Some(content_etag(&data));
+        Ok(StoredObject { data, etag })
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>, condition: WriteCondition) -> Result<(), LambdaError> {
+        let mut objects = self.objects.lock().expect("memory storage lock poisoned");
+        let current = objects.get(key).map(|data| content_etag(data.as_slice()));
+        let satisfied = match &condition {
+            WriteCondition::Always => true,
+            WriteCondition::IfMatch(expected) => current.as_ref() == Some(expected),
+            WriteCondition::IfAbsent => current.is_none(),
+        };
+        if !satisfied {
+            return Err(precondition_failed("memory", key));
+        }
+        objects.insert(key.to_string(), data);
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<(), LambdaError> {
+        let objects = self.objects.lock().expect("memory storage lock poisoned");
+        if objects.contains_key(key) {
+            Ok(())
+        } else {
+            Err(MemoryStorage::not_found(key))
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, LambdaError> {
+        let objects = self.objects.lock().expect("memory storage lock poisoned");
+        let mut keys: Vec<String> = objects.keys().filter(|key| key.starts_with(prefix)).cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+// 根据配置创建存储后端；只在 main 中调用一次，S3Client 和凭证在之后的调用中复用
+pub async fn from_config(config: &StorageConfig) -> Arc<dyn Storage> {
+    let backend: Arc<dyn Storage> = match config.backend {
+        StorageBackend::S3 => {
+            // 重试、退避和熔断都由外层的 RetryingStorage 负责；SDK 自己再重试会让尝试次数相乘并超过 deadline
+            let sdk_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+                .retry_config(aws_config::retry::RetryConfig::disabled())
+                .load()
+                .await;
+            Arc::new(S3Storage::new(S3Client::new(&sdk_config), config.bucket.clone()))
+        }
+        StorageBackend::Local => Arc::new(LocalStorage::new(config.local_dir.clone())),
+        StorageBackend::Memory => Arc::new(MemoryStorage::new()),
+    };
+    // 熔断器的状态保存在这个实例中，所以同样只创建一次
+    Arc::new(RetryingStorage::new(backend, config.retry.clone()))
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    // 测试用的故障存储：调用转发到内存存储，按配置注入错误、延迟和让出执行权
+    #[derive(Default)]
+    pub struct FaultStorage {
+        pub inner: MemoryStorage,
+        // 按顺序返回的错误 (任意操作)，用完后转发
+        faults: Mutex<VecDeque<StorageErrorKind>>,
+        // 某个操作一直返回的错误，优先于 outage
+        failing: Mutex<HashMap<&'static str, StorageErrorKind>>,
+        // 所有操作一直返回的错误，模拟存储不可用
+        outage: Mutex<Option<StorageErrorKind>>,
+        delay: Duration,
+        // get 返回后让出执行权，让并发的读-改-写交错执行
+        yield_after_get: bool,
+        calls: AtomicUsize,
+        puts: AtomicUsize,
+    }
+
+    impl FaultStorage {
+        pub fn new() -> Self {
+            FaultStorage::default()
+        }
+
+        pub fn with_faults(mut self, faults: &[StorageErrorKind]) -> Self {
+            self.faults = Mutex::new(faults.iter().copied().collect());
+            self
+        }
+
+        pub fn with_delay(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+
+        pub fn yielding(mut self) -> Self {
+            self.yield_after_get = true;
+            self
+        }
+
+        pub fn fail(&self, operation: &'static str, kind: StorageErrorKind) {
+            self.failing.lock().unwrap().insert(operation, kind);
+        }
+
+        pub fn fail_all(&self, kind: StorageErrorKind) {
+            *self.outage.lock().unwrap() = Some(kind);
+        }
+
+        pub fn recover(&self) {
+            self.failing.lock().unwrap().clear();
+            *self.outage.lock().unwrap() = None;
+        }
+
+        pub fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        pub fn puts(&self) -> usize {
+            self.puts.load(Ordering::SeqCst)
+        }
+
+        async fn fault(&self, operation: &'static str, key: &str) -> Result<(), LambdaError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if operation == "put" {
+                self.puts.fetch_add(1, Ordering::SeqCst);
+            }
+            if !self.delay.is_zero() {
+                tokio::time::sleep(self.delay).await;
+            }
+            let failing = self.failing.lock().unwrap().get(operation).copied();
+            let kind = failing
+                .or(*self.outage.lock().unwrap())
+                .or_else(|| self.faults.lock().unwrap().pop_front());
+            match kind {
+                Some(kind) => Err(LambdaError::storage("fault", operation, key, kind, std::io::Error::other("injected fault"))),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Storage for FaultStorage {
+        fn backend(&self) -> &'static str {
+            "fault"
+        }
+
+        fn location(&self, key: &str) -> String {
+            format!("fault://{}", key)
+        }
+
+        async fn get(&self, key: &str) -> Result<StoredObject, LambdaError> {
+            self.fault("get", key).await?;
+            let object = self.inner.get(key).await;
+            if self.yield_after_get {
+                tokio::task::yield_now().await;
+            }
+            object
+        }
+
+        async fn put(&self, key: &str, data: Vec<u8>, condition: WriteCondition) -> Result<(), LambdaError> {
+            self.fault("put", key).await?;
+            self.inner.put(key, data, condition).await
+        }
+
+        async fn head(&self, key: &str) -> Result<(), LambdaError> {
+            self.fault("head", key).await?;
+            self.inner.head(key).await
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<String>, LambdaError> {
+            self.fault("list", prefix).await?;
+            self.inner.list(prefix).await
+        }
+
+        async fn head_bucket(&self) -> Result<(), LambdaError> {
+            self.fault("head_bucket", "").await?;
+            self.inner.head_bucket().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_storage_round_trip() {
+        let storage = MemoryStorage::new();
+        let err = storage.get("sentiment.csv").await.unwrap_err();
+        assert!(matches!(err, LambdaError::Storage { kind: StorageErrorKind::NoSuchKey, .. }));
+
+        storage.put("sentiment.csv", b"Sentiment,Count\n".to_vec(), WriteCondition::Always).await.unwrap();
+        assert!(storage.head("sentiment.csv").await.is_ok());
+        assert_eq!(storage.get("sentiment.csv").await.unwrap().data, b"Sentiment,Count\n".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage_if_match() {
+        let storage = MemoryStorage::new();
+        storage.put("sentiment.csv", b"v1".to_vec(), WriteCondition::Always).await.unwrap();
+        let stale = storage.get("sentiment.csv").await.unwrap().etag.unwrap();
+
+        storage.put("sentiment.csv", b"v2".to_vec(), WriteCondition::IfMatch(stale.clone())).await.unwrap();
+        let err = storage.put("sentiment.csv", b"v3".to_vec(), WriteCondition::IfMatch(stale)).await.unwrap_err();
+        assert_eq!(err.storage_kind(), Some(StorageErrorKind::PreconditionFailed));
+        assert_eq!(storage.get("sentiment.csv").await.unwrap().data, b"v2".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_local_storage_round_trip() {
+        let root = std::env::temp_dir().join(format!("rust_lambda_hf_storage_{}", std::process::id()));
+        let storage = LocalStorage::new(root.clone());
+        assert!(storage.head("nested/sentiment.csv").await.is_err());
+
+        storage.put("nested/sentiment.csv", b"Positive,1\n".to_vec(), WriteCondition::Always).await.unwrap();
+        let object = storage.get("nested/sentiment.csv").await.unwrap();
+        assert_eq!(object.data, b"Positive,1\n".to_vec());
+
+        let err = storage.put("nested/sentiment.csv", Vec::new(), WriteCondition::IfMatch("\"stale\"".into())).await.unwrap_err();
+        assert_eq!(err.storage_kind(), Some(StorageErrorKind::PreconditionFailed));
+        let err = storage.put("nested/sentiment.csv", Vec::new(), WriteCondition::IfAbsent).await.unwrap_err();
+        assert_eq!(err.storage_kind(), Some(StorageErrorKind::PreconditionFailed));
+
+        storage.put("nested/deeper/other.csv", Vec::new(), WriteCondition::IfAbsent).await.unwrap();
+        assert_eq!(
+            storage.list("nested/").await.unwrap(),
+            vec!["nested/deeper/other.csv".to_string(), "nested/sentiment.csv".to_string()]
+        );
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+}